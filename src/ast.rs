@@ -0,0 +1,188 @@
+//! Parsed representation of a template.
+
+use crate::sample::ChoiceGuidance;
+use std::ops::Range;
+
+/// Byte range into the template source.
+pub type Span = Range<usize>;
+
+/// A piece of an option: literal text, a nested group to choose from, or a reference to a
+/// variable by its index in the template's variable table.
+#[derive(Clone, Debug)]
+pub enum Node {
+    Text(String),
+    Group(Group),
+    Variable(usize),
+}
+
+/// A curly brace delimited set of options, one of which is chosen on each generation.
+///
+/// The template itself is also a group: top level pipes separate options just as they would
+/// within braces.
+#[derive(Clone, Debug)]
+pub struct Group {
+    /// Number identifying the group within its template, unique even between the uses of one
+    /// wildcard, for keeping track of it from one prompt to the next.
+    pub id: usize,
+    pub span: Span,
+    pub options: Vec<Choice>,
+    /// Name of the wildcard file this group's options were read from, if any. Spans within
+    /// the options refer to that file rather than the template.
+    pub wildcard: Option<String>,
+    /// Path of the file included with `{@path}` that this group's options were read from, if
+    /// any, as written in the template. Spans within the options refer to that file.
+    pub include: Option<String>,
+    /// Index of the source the options were read from, within [`Template::sources`], if it
+    /// differs from the one the group itself appears in.
+    ///
+    /// [`Template::sources`]: crate::Template::sources
+    pub source: Option<usize>,
+    /// Set for groups that choose several options at once.
+    pub selection: Option<Selection>,
+    /// Guidance given for this group alone with `{!name: ...}`, taking the place of
+    /// [`GenerationOptions::choice_guidance`].
+    ///
+    /// [`GenerationOptions::choice_guidance`]: crate::GenerationOptions::choice_guidance
+    pub guidance: Option<ChoiceGuidance>,
+    /// Temperature given for this group alone with `{~0.5: ...}`, taking the place of
+    /// [`GenerationOptions::temperature`].
+    ///
+    /// [`GenerationOptions::temperature`]: crate::GenerationOptions::temperature
+    pub temperature: Option<f64>,
+    /// Index of the option this group is locked to, if any, leaving every other option out
+    /// of those the sampler may pick whatever their weights or the guidance. Never set by
+    /// parsing; used to hold part of a template still while the rest is rerolled.
+    pub locked: Option<usize>,
+}
+
+/// Number of distinct options to choose from a group at once, written as `{2$$...}` for
+/// exactly two or `{1-3$$...}` for between one and three, with a separator to join them given
+/// as `{2$$ and $$...}`.
+#[derive(Clone, Debug)]
+pub struct Selection {
+    pub min: usize,
+    pub max: usize,
+    pub separator: String,
+}
+
+impl Selection {
+    pub const DEFAULT_SEPARATOR: &'static str = ", ";
+
+    /// The range of options to select, limited to the number that are `available`.
+    pub fn bounds(&self, available: usize) -> (usize, usize) {
+        let max = self.max.min(available);
+        (self.min.min(max), max)
+    }
+}
+
+impl Group {
+    /// Name of the wildcard or included file the options were read from, if any.
+    pub fn file(&self) -> Option<&String> {
+        self.wildcard.as_ref().or(self.include.as_ref())
+    }
+
+    /// The options that can actually be chosen, along with their indices: those with a
+    /// positive weight, or only the last option if every weight is zero. A locked group can
+    /// only choose the option it is locked to.
+    pub fn possible_options(&self) -> impl Iterator<Item = (usize, &Choice)> {
+        let any_weighted = self.options.iter().any(|choice| choice.weight > 0.0);
+        let last = self.options.len() - 1;
+        let locked = self.locked;
+        self.options
+            .iter()
+            .enumerate()
+            .filter(move |&(index, choice)| {
+                if let Some(locked) = locked {
+                    index == locked
+                } else if any_weighted {
+                    choice.weight > 0.0
+                } else {
+                    index == last
+                }
+            })
+    }
+
+    /// The weight of every option, reshaped by `temperature`, with those that can't be chosen
+    /// at zero. A temperature of 1 keeps the weights as written; lower temperatures favour the
+    /// heaviest options more and more, down to only ever choosing between the heaviest at 0,
+    /// while higher temperatures even the weights out towards a uniform choice.
+    pub fn weights(&self, temperature: f64) -> Vec<f64> {
+        let mut weights = vec![0.0; self.options.len()];
+        let max = self
+            .possible_options()
+            .map(|(_, choice)| choice.weight)
+            .fold(0.0, f64::max);
+        for (index, choice) in self.possible_options() {
+            weights[index] = if temperature == 1.0 {
+                choice.weight
+            } else if max <= 0.0 {
+                1.0
+            } else if temperature == 0.0 {
+                (choice.weight == max) as u8 as f64
+            } else {
+                // Scaled by the heaviest weight first, so large exponents can't overflow
+                (choice.weight / max).powf(temperature.recip())
+            };
+        }
+        weights
+    }
+
+    /// This group along with every group nested within it.
+    pub fn nested(&self) -> Vec<&Group> {
+        let mut nested = Vec::new();
+        let mut groups = vec![self];
+        while let Some(group) = groups.pop() {
+            nested.push(group);
+            for node in group.options.iter().flat_map(|choice| &choice.nodes) {
+                if let Node::Group(group) = node {
+                    groups.push(group);
+                }
+            }
+        }
+        nested
+    }
+
+    /// Indices of every variable referenced from within this group, including nested groups.
+    pub fn references(&self) -> Vec<usize> {
+        let mut references = Vec::new();
+        let mut groups = vec![self];
+        while let Some(group) = groups.pop() {
+            for node in group.options.iter().flat_map(|choice| &choice.nodes) {
+                match node {
+                    Node::Text(_) => {}
+                    Node::Group(group) => groups.push(group),
+                    Node::Variable(index) => references.push(*index),
+                }
+            }
+        }
+        references
+    }
+}
+
+/// A single option within a group.
+#[derive(Clone, Debug)]
+pub struct Choice {
+    pub span: Span,
+    pub nodes: Vec<Node>,
+    pub weight: f64,
+}
+
+impl Choice {
+    pub(crate) fn new(start: usize) -> Self {
+        Self {
+            span: start..start,
+            nodes: Vec::new(),
+            weight: 1.0,
+        }
+    }
+}
+
+/// A group bound to a name with `{$name=...}`. The group is chosen from once per generated
+/// prompt, and every reference to the variable expands to that same choice.
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub group: Group,
+    /// Index of the source the variable is defined in, which the group's spans refer to.
+    pub source_index: usize,
+}
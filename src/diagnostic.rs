@@ -0,0 +1,176 @@
+//! Rendering of parse errors and lints against the source text they occurred in.
+
+use crate::ast::Span;
+use crate::parse::ParseError;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+
+/// A piece of template source text, such as the template itself or a wildcard file.
+#[derive(Clone, Debug)]
+pub struct Source {
+    /// Name to refer to the source by in diagnostics, usually its file path.
+    pub name: String,
+    /// Shared between every template parsed from the same file with [`parse_all`].
+    ///
+    /// [`parse_all`]: crate::parse_all
+    pub text: Arc<str>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A message about a span of source text, located by 1-based line and character column.
+/// Displays with the offending line quoted and the span underlined.
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Short identifier for the kind of problem, such as `unclosed_brace`.
+    pub code: &'static str,
+    pub message: String,
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    /// The full text of the line the span starts on.
+    #[serde(skip)]
+    pub line_text: String,
+}
+
+/// The 1-based line and character column of the byte index `byte` in `text`.
+pub fn line_column(text: &str, byte: usize) -> (usize, usize) {
+    let before = &text[..byte];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+impl Diagnostic {
+    pub fn new(
+        severity: Severity,
+        code: &'static str,
+        message: String,
+        source: &Source,
+        span: &Span,
+    ) -> Self {
+        let (line, column) = line_column(&source.text, span.start);
+        let (end_line, end_column) = line_column(&source.text, span.end);
+        let line_start = source.text[..span.start]
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        let line_text = source.text[line_start..].lines().next().unwrap_or("");
+        Self {
+            severity,
+            code,
+            message,
+            file: source.name.clone(),
+            line,
+            column,
+            end_line,
+            end_column,
+            line_text: line_text.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Diagnostic {
+            severity,
+            message,
+            file,
+            line,
+            column,
+            line_text,
+            ..
+        } = self;
+        let line_length = line_text.chars().count() + 1;
+        let end_column = if self.end_line == *line {
+            self.end_column
+        } else {
+            line_length
+        };
+        let underlined = end_column.saturating_sub(*column).max(1);
+        // Keep tabs in the padding so the caret lines up however wide they are displayed
+        let padding: String = line_text
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let gutter = " ".repeat(line.to_string().len());
+        writeln!(f, "{severity}: {message}")?;
+        writeln!(f, "{gutter}--> {file}:{line}:{column}")?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{line} | {line_text}")?;
+        write!(f, "{gutter} | {padding}{}", "^".repeat(underlined))
+    }
+}
+
+/// Display a list of diagnostics separated by blank lines, followed by a count if there are
+/// several.
+pub(crate) fn display_all(
+    f: &mut fmt::Formatter<'_>,
+    diagnostics: &[Diagnostic],
+    noun: &str,
+) -> fmt::Result {
+    for (index, diagnostic) in diagnostics.iter().enumerate() {
+        if index > 0 {
+            write!(f, "\n\n")?;
+        }
+        write!(f, "{diagnostic}")?;
+    }
+    if diagnostics.len() > 1 {
+        write!(f, "\n\n{} {noun}s", diagnostics.len())?;
+    }
+    Ok(())
+}
+
+/// Every error found while parsing a template, along with the sources they occurred in.
+/// Displays as a list of diagnostics, each quoting the offending line.
+#[derive(Clone, Debug)]
+pub struct ParseErrors {
+    pub errors: Vec<ParseError>,
+    /// The sources that [`ParseError::source_index`] refers into.
+    pub sources: Vec<Source>,
+}
+
+impl ParseErrors {
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.errors
+            .iter()
+            .map(|error| {
+                Diagnostic::new(
+                    Severity::Error,
+                    error.kind.code(),
+                    error.to_string(),
+                    &self.sources[error.source_index],
+                    &error.span,
+                )
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        display_all(f, &self.diagnostics(), "error")
+    }
+}
+
+impl std::error::Error for ParseErrors {}
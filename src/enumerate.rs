@@ -0,0 +1,176 @@
+//! Exhaustive, deterministic enumeration of every prompt a template can produce.
+
+use crate::ast::{Group, Node};
+use crate::prompt::{Prompt, Trace};
+use crate::Template;
+use num_bigint::BigUint;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Error)]
+#[error("Template has {count} combinations, more than the limit of {limit}")]
+pub struct TooManyCombinations {
+    pub count: BigUint,
+    pub limit: u64,
+}
+
+fn binomial(n: usize, k: usize) -> usize {
+    (0..k).fold(1usize, |product, i| product.saturating_mul(n - i) / (i + 1))
+}
+
+/// The `rank`th way, in lexicographic order, to choose `k` of `n` items.
+fn nth_combination(n: usize, k: usize, mut rank: usize) -> Vec<usize> {
+    let mut combination = Vec::with_capacity(k);
+    let mut next = 0;
+    while combination.len() < k {
+        let remaining = k - combination.len() - 1;
+        let with_next = binomial(n - next - 1, remaining);
+        if rank < with_next {
+            combination.push(next);
+        } else {
+            rank -= with_next;
+        }
+        next += 1;
+    }
+    combination
+}
+
+/// Iterator over every combination of choices in a template, in a fixed order: the first
+/// option of every group first, then counting up through the options of the last group
+/// visited, like an odometer. Only one combination is held in memory at a time.
+pub struct Combinations<'a> {
+    template: &'a Template,
+    /// Index of the option taken at each group visited for the current combination, in
+    /// visiting order.
+    trail: Vec<usize>,
+    /// Number of options available at each of those groups.
+    counts: Vec<usize>,
+    position: usize,
+    values: Vec<Option<String>>,
+    trace: Trace,
+    done: bool,
+}
+
+impl<'a> Combinations<'a> {
+    pub fn new(template: &'a Template) -> Self {
+        Self {
+            template,
+            trail: Vec::new(),
+            counts: Vec::new(),
+            position: 0,
+            values: vec![None; template.variables.len()],
+            trace: Trace::default(),
+            done: false,
+        }
+    }
+
+    /// Take the decision for the next group visited, defaulting to its first option when the
+    /// group has not been visited in this combination before.
+    fn decide(&mut self, count: usize) -> usize {
+        if self.position == self.trail.len() {
+            self.trail.push(0);
+            self.counts.push(count);
+        }
+        self.position += 1;
+        self.trail[self.position - 1]
+    }
+
+    fn expand_group(&mut self, group: &Group, out: &mut String) {
+        let options: Vec<_> = group.possible_options().collect();
+        let Some(selection) = &group.selection else {
+            let (index, _) = options[self.decide(options.len())];
+            self.expand_choice(group, index, out);
+            return;
+        };
+        // Sets of options are ordered by size, then lexicographically.
+        let (min, max) = selection.bounds(options.len());
+        let sizes: Vec<_> = (min..=max)
+            .map(|k| (k, binomial(options.len(), k)))
+            .collect();
+        let mut rank = self.decide(sizes.iter().map(|(_, count)| count).sum());
+        let (k, _) = sizes
+            .into_iter()
+            .find(|&(_, count)| {
+                let found = rank < count;
+                if !found {
+                    rank -= count;
+                }
+                found
+            })
+            .unwrap();
+        for (n, position) in nth_combination(options.len(), k, rank)
+            .into_iter()
+            .enumerate()
+        {
+            if n > 0 {
+                out.push_str(&selection.separator);
+            }
+            self.expand_choice(group, options[position].0, out);
+        }
+    }
+
+    fn expand_choice(&mut self, group: &Group, index: usize, out: &mut String) {
+        let start = out.len();
+        let checkpoint = self.trace.begin(group, index, 1.0);
+        for node in &group.options[index].nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Group(group) => self.expand_group(group, out),
+                Node::Variable(index) => out.push_str(self.variable(*index)),
+            }
+        }
+        self.trace.end(checkpoint, &out[start..]);
+    }
+
+    fn variable(&mut self, index: usize) -> &str {
+        if self.values[index].is_none() {
+            let mut value = String::new();
+            self.expand_group(&self.template.variables[index].group, &mut value);
+            self.values[index] = Some(value);
+        }
+        self.values[index].as_deref().unwrap()
+    }
+
+    /// Move the trail on to the next combination, returning `false` once every combination
+    /// has been visited.
+    fn advance(&mut self) -> bool {
+        let Some(last) = (0..self.trail.len())
+            .rev()
+            .find(|&position| self.trail[position] + 1 < self.counts[position])
+        else {
+            return false;
+        };
+        self.trail.truncate(last + 1);
+        self.counts.truncate(last + 1);
+        self.trail[last] += 1;
+        true
+    }
+}
+
+impl Iterator for Combinations<'_> {
+    type Item = Prompt;
+
+    fn next(&mut self) -> Option<Prompt> {
+        if self.done {
+            return None;
+        }
+        self.position = 0;
+        self.values.fill(None);
+        for index in 0..self.values.len() {
+            self.variable(index);
+        }
+        let mut out = String::new();
+        self.expand_group(&self.template.root, &mut out);
+        let negative = self.template.negative.as_ref().map(|negative| {
+            let mut out = String::new();
+            self.expand_group(negative, &mut out);
+            out
+        });
+        self.done = !self.advance();
+        Some(Prompt {
+            text: out,
+            negative,
+            seed: None,
+            choices: std::mem::take(&mut self.trace.picks),
+        })
+    }
+}
@@ -0,0 +1,712 @@
+//! Interactive editing of a template in the terminal, with a live preview of the prompts it
+//! generates.
+
+use crate::ast::{Group, Node, Span};
+use crate::diagnostic::ParseErrors;
+use crate::parse::ParseOptions;
+use crate::prompt::Prompt;
+use crate::rng::prompt_seed;
+use crate::sample::{Batch, GenerationOptions};
+use crate::Template;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use rand::random;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+const HELP: &str = "Ctrl+R reroll · Ctrl+L lock group · Ctrl+S save · Esc quit";
+
+/// A group fixed to one of its options while the rest of the template is rerolled.
+#[derive(Clone, Copy, Debug)]
+struct Lock {
+    /// Byte index of the group's opening brace in the template.
+    start: usize,
+    option: usize,
+}
+
+/// What the template currently generates.
+enum Preview {
+    Prompts {
+        prompts: Vec<Prompt>,
+        /// Spans of the groups fixed by locks.
+        locked: Vec<Span>,
+    },
+    Errors(ParseErrors),
+}
+
+/// A line of the template as displayed, wrapped to the width of the terminal.
+struct Row {
+    start: usize,
+    end: usize,
+    /// Whether this is the last row of its line, rather than one broken off by wrapping.
+    last: bool,
+}
+
+/// Puts the terminal into raw mode on the alternate screen, restoring it when dropped.
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        Ok(Screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), LeaveAlternateScreen, Show);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// An editing session: the template being edited along with a preview of `num` prompts
+/// generated from it, refreshed on every change.
+pub struct Editor {
+    text: String,
+    /// Byte index of the cursor within `text`.
+    cursor: usize,
+    /// File the template is saved to, if any.
+    path: Option<PathBuf>,
+    modified: bool,
+    parse_options: ParseOptions,
+    options: GenerationOptions,
+    num: usize,
+    seed: u64,
+    locks: Vec<Lock>,
+    preview: Preview,
+    /// First row of the template on screen.
+    scroll: usize,
+    width: usize,
+    /// Message shown in place of the key bindings until the next key press.
+    status: Option<String>,
+    /// Set once warned about quitting with unsaved changes, until the next key press.
+    quitting: bool,
+}
+
+impl Editor {
+    /// Start editing `text`, which is saved to `path` if given, previewing `num` prompts at a
+    /// time generated from `seed`.
+    pub fn new(
+        text: String,
+        path: Option<PathBuf>,
+        parse_options: ParseOptions,
+        options: GenerationOptions,
+        num: usize,
+        seed: u64,
+    ) -> Self {
+        let mut editor = Self {
+            cursor: text.len(),
+            text,
+            path,
+            modified: false,
+            parse_options,
+            options,
+            num,
+            seed,
+            locks: Vec::new(),
+            preview: Preview::Prompts {
+                prompts: Vec::new(),
+                locked: Vec::new(),
+            },
+            scroll: 0,
+            width: 80,
+            status: None,
+            quitting: false,
+        };
+        editor.refresh();
+        editor
+    }
+
+    /// Run the editor until it's quit, returning the template as last edited.
+    pub fn run(mut self) -> io::Result<String> {
+        let _screen = Screen::enter()?;
+        let mut out = io::stdout();
+        loop {
+            self.draw(&mut out)?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Release && !self.key(key) {
+                    break;
+                }
+            }
+        }
+        Ok(self.text)
+    }
+
+    /// Handle a key press, returning whether to carry on editing.
+    fn key(&mut self, key: KeyEvent) -> bool {
+        self.status = None;
+        let quitting = std::mem::take(&mut self.quitting);
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        if key.code == KeyCode::Esc || (control && matches!(key.code, KeyCode::Char('c' | 'q'))) {
+            if !self.modified || self.path.is_none() || quitting {
+                return false;
+            }
+            self.status = Some("Unsaved changes; press Esc again to quit without saving".into());
+            self.quitting = true;
+            return true;
+        }
+        match key.code {
+            KeyCode::Char('r') if control => {
+                self.seed = random();
+                self.refresh();
+            }
+            KeyCode::Char('l') if control => self.toggle_lock(),
+            KeyCode::Char('s') if control => self.save(),
+            KeyCode::Char(c) if !control => self.insert(c.encode_utf8(&mut [0; 4])),
+            KeyCode::Enter => self.insert("\n"),
+            KeyCode::Backspace => {
+                if let Some(c) = self.text[..self.cursor].chars().next_back() {
+                    self.remove(self.cursor - c.len_utf8()..self.cursor);
+                }
+            }
+            KeyCode::Delete => {
+                if let Some(c) = self.text[self.cursor..].chars().next() {
+                    self.remove(self.cursor..self.cursor + c.len_utf8());
+                }
+            }
+            KeyCode::Left => {
+                if let Some(c) = self.text[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                }
+            }
+            KeyCode::Right => {
+                if let Some(c) = self.text[self.cursor..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+            }
+            KeyCode::Up => self.move_rows(-1),
+            KeyCode::Down => self.move_rows(1),
+            KeyCode::Home => {
+                let rows = self.rows();
+                self.cursor = rows[self.cursor_position(&rows).0].start;
+            }
+            KeyCode::End => {
+                let rows = self.rows();
+                let row = self.cursor_position(&rows).0;
+                self.cursor = self.column(&rows[row], usize::MAX);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.text.insert_str(self.cursor, text);
+        for lock in &mut self.locks {
+            if lock.start >= self.cursor {
+                lock.start += text.len();
+            }
+        }
+        self.cursor += text.len();
+        self.modified = true;
+        self.refresh();
+    }
+
+    fn remove(&mut self, range: Span) {
+        self.text.replace_range(range.clone(), "");
+        self.locks.retain(|lock| !range.contains(&lock.start));
+        for lock in &mut self.locks {
+            if lock.start >= range.end {
+                lock.start -= range.len();
+            }
+        }
+        self.cursor = range.start;
+        self.modified = true;
+        self.refresh();
+    }
+
+    fn save(&mut self) {
+        let Some(path) = &self.path else {
+            self.status = Some("No file to save to; the template is printed on quitting".into());
+            return;
+        };
+        let mut text = self.text.clone();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        self.status = Some(match fs::write(path, text) {
+            Ok(()) => {
+                self.modified = false;
+                format!("Saved to {}", path.display())
+            }
+            Err(err) => format!("Could not save to {}: {err}", path.display()),
+        });
+    }
+
+    /// Parse the template again and generate a new preview from it.
+    fn refresh(&mut self) {
+        self.preview = match Template::parse(&self.text, &self.parse_options) {
+            Ok(mut template) => {
+                let mut locked = Vec::new();
+                for variable in &mut template.variables {
+                    if variable.source_index == 0 {
+                        apply_lock(&mut variable.group, &self.locks, &mut locked);
+                    }
+                }
+                for root in std::iter::once(&mut template.root).chain(&mut template.negative) {
+                    apply_locks(root, &self.locks, &mut locked);
+                }
+                // Locks on groups that no longer exist are forgotten
+                self.locks
+                    .retain(|lock| locked.iter().any(|span| span.start == lock.start));
+                let mut batch = Batch::new(self.num);
+                let prompts = (0..self.num as u64)
+                    .map(|index| {
+                        let seed = prompt_seed(self.seed, index);
+                        template.generate_in_batch(seed, &self.options, &mut batch)
+                    })
+                    .collect();
+                Preview::Prompts { prompts, locked }
+            }
+            Err(errors) => Preview::Errors(errors),
+        };
+    }
+
+    /// Lock the innermost group around the cursor to the option the cursor is in, or unlock it
+    /// if it already is.
+    fn toggle_lock(&mut self) {
+        let template = match Template::parse(&self.text, &self.parse_options) {
+            Ok(template) => template,
+            Err(_) => {
+                self.status = Some("Fix the errors in the template before locking".to_string());
+                return;
+            }
+        };
+        // Variables are defined apart from where they appear, so a group within a variable
+        // can sit inside a group from the rest of the template
+        let variables = template
+            .variables
+            .iter()
+            .filter(|variable| variable.source_index == 0)
+            .map(|variable| &variable.group)
+            .filter(|group| inside(group, self.cursor))
+            .map(|group| group_at(group, self.cursor).unwrap_or(group));
+        let group = template
+            .roots()
+            .filter_map(|root| group_at(root, self.cursor))
+            .chain(variables)
+            .min_by_key(|group| group.span.len());
+        let Some(group) = group else {
+            self.status = Some("Move the cursor inside a group to lock it".to_string());
+            return;
+        };
+        let option = group
+            .options
+            .iter()
+            .position(|choice| self.cursor <= choice.span.end)
+            .unwrap_or(group.options.len() - 1);
+        let existing = self
+            .locks
+            .iter()
+            .position(|lock| lock.start == group.span.start);
+        self.status = Some(match existing {
+            Some(index) if self.locks[index].option == option => {
+                self.locks.remove(index);
+                "Unlocked group".to_string()
+            }
+            Some(index) => {
+                self.locks[index].option = option;
+                format!("Locked group to option {}", option + 1)
+            }
+            None => {
+                self.locks.push(Lock {
+                    start: group.span.start,
+                    option,
+                });
+                format!("Locked group to option {}", option + 1)
+            }
+        });
+        self.refresh();
+    }
+
+    /// The template broken into rows no wider than the terminal.
+    fn rows(&self) -> Vec<Row> {
+        let mut rows = Vec::new();
+        let mut start = 0;
+        for line in self.text.split('\n') {
+            let mut row_start = start;
+            for (count, (offset, _)) in line.char_indices().enumerate() {
+                if count > 0 && count % self.width == 0 {
+                    rows.push(Row {
+                        start: row_start,
+                        end: start + offset,
+                        last: false,
+                    });
+                    row_start = start + offset;
+                }
+            }
+            rows.push(Row {
+                start: row_start,
+                end: start + line.len(),
+                last: true,
+            });
+            start += line.len() + 1;
+        }
+        rows
+    }
+
+    /// The row and column the cursor is displayed at.
+    fn cursor_position(&self, rows: &[Row]) -> (usize, usize) {
+        let row = rows
+            .iter()
+            .position(|row| {
+                row.start <= self.cursor
+                    && (self.cursor < row.end || (self.cursor == row.end && row.last))
+            })
+            .unwrap_or(rows.len() - 1);
+        let column = self.text[rows[row].start..self.cursor].chars().count();
+        (row, column)
+    }
+
+    /// Byte index of `column` within `row`, or of the end of the row if it's shorter.
+    fn column(&self, row: &Row, column: usize) -> usize {
+        let text = &self.text[row.start..row.end];
+        match text.char_indices().nth(column) {
+            Some((offset, _)) => row.start + offset,
+            // The end of a wrapped row is displayed at the start of the next one
+            None if !row.last => row.start + text.char_indices().last().map_or(0, |(i, _)| i),
+            None => row.end,
+        }
+    }
+
+    fn move_rows(&mut self, delta: isize) {
+        let rows = self.rows();
+        let (row, column) = self.cursor_position(&rows);
+        if let Some(row) = row
+            .checked_add_signed(delta)
+            .filter(|&row| row < rows.len())
+        {
+            self.cursor = self.column(&rows[row], column);
+        }
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let (columns, height) = terminal::size()?;
+        self.width = (columns as usize).max(1);
+        let height = height as usize;
+        queue!(out, Hide, Clear(ClearType::All), MoveTo(0, 0))?;
+        let header = self.status.as_deref().unwrap_or(HELP);
+        queue!(
+            out,
+            SetAttribute(Attribute::Reverse),
+            Print(fit(header, self.width)),
+            SetAttribute(Attribute::Reset),
+        )?;
+
+        // The template, with errors and locked groups highlighted
+        let rows = self.rows();
+        let (cursor_row, cursor_column) = self.cursor_position(&rows);
+        let editor_height = rows.len().clamp(1, (height.saturating_sub(4) / 2).max(1));
+        if cursor_row < self.scroll {
+            self.scroll = cursor_row;
+        } else if cursor_row >= self.scroll + editor_height {
+            self.scroll = cursor_row + 1 - editor_height;
+        }
+        let (errors, locked) = match &self.preview {
+            Preview::Errors(errors) => (
+                errors
+                    .errors
+                    .iter()
+                    .filter(|error| error.source_index == 0)
+                    .map(|error| error.span.clone())
+                    .collect(),
+                Vec::new(),
+            ),
+            Preview::Prompts { locked, .. } => (Vec::new(), locked.clone()),
+        };
+        for (index, row) in rows
+            .iter()
+            .skip(self.scroll)
+            .take(editor_height)
+            .enumerate()
+        {
+            queue!(out, MoveTo(0, 1 + index as u16))?;
+            let mut color = None;
+            for (offset, c) in self.text[row.start..row.end].char_indices() {
+                let byte = row.start + offset;
+                let error = errors.iter().any(|span: &Span| {
+                    span.contains(&byte) || (span.is_empty() && span.start == byte)
+                });
+                let new = if error {
+                    Some(Color::Red)
+                } else if locked.iter().any(|span| span.contains(&byte)) {
+                    Some(Color::Cyan)
+                } else {
+                    None
+                };
+                if new != color {
+                    match new {
+                        Some(new) => queue!(out, SetForegroundColor(new))?,
+                        None => queue!(out, ResetColor)?,
+                    }
+                    color = new;
+                }
+                queue!(out, Print(if c == '\t' { ' ' } else { c }))?;
+            }
+            queue!(out, ResetColor)?;
+        }
+
+        // The preview, or the errors keeping there from being one
+        let mut line = 1 + editor_height;
+        let title = match &self.preview {
+            Preview::Prompts { .. } => format!("── preview, seed {} ", self.seed),
+            Preview::Errors(errors) => match errors.errors.len() {
+                1 => "── 1 error ".to_string(),
+                count => format!("── {count} errors "),
+            },
+        };
+        queue!(
+            out,
+            MoveTo(0, line as u16),
+            Print(fit(
+                &format!("{title}{}", "─".repeat(self.width)),
+                self.width
+            ))
+        )?;
+        line += 1;
+        let mut lines: Vec<(String, Option<Color>)> = Vec::new();
+        match &self.preview {
+            Preview::Prompts { prompts, .. } => {
+                for prompt in prompts {
+                    lines.extend(prompt.text.split('\n').map(|text| (text.to_string(), None)));
+                    if let Some(negative) = &prompt.negative {
+                        let negative = format!("Negative prompt: {negative}");
+                        lines.extend(
+                            negative
+                                .split('\n')
+                                .map(|text| (text.to_string(), Some(Color::DarkGrey))),
+                        );
+                    }
+                }
+            }
+            Preview::Errors(errors) => {
+                for diagnostic in errors.diagnostics() {
+                    let text = format!(
+                        "{}:{}:{}: {}",
+                        diagnostic.file, diagnostic.line, diagnostic.column, diagnostic.message
+                    );
+                    lines.push((text, Some(Color::Red)));
+                }
+            }
+        }
+        for (text, color) in lines {
+            let chars: Vec<char> = text.chars().collect();
+            let mut chunks: Vec<String> = chars
+                .chunks(self.width)
+                .map(|chunk| chunk.iter().collect())
+                .collect();
+            if chunks.is_empty() {
+                chunks.push(String::new());
+            }
+            for chunk in chunks {
+                if line >= height {
+                    break;
+                }
+                queue!(out, MoveTo(0, line as u16))?;
+                if let Some(color) = color {
+                    queue!(out, SetForegroundColor(color))?;
+                }
+                queue!(out, Print(chunk), ResetColor)?;
+                line += 1;
+            }
+        }
+
+        let cursor_line = 1 + cursor_row - self.scroll;
+        queue!(
+            out,
+            MoveTo(cursor_column.min(self.width - 1) as u16, cursor_line as u16),
+            Show
+        )?;
+        out.flush()
+    }
+}
+
+/// Whether `position` is within the braces of `group`.
+fn inside(group: &Group, position: usize) -> bool {
+    group.span.start < position && position < group.span.end
+}
+
+/// The innermost group nested within `group` with `position` inside its braces, leaving out
+/// those read from other files.
+fn group_at(group: &Group, position: usize) -> Option<&Group> {
+    group
+        .options
+        .iter()
+        .flat_map(|choice| &choice.nodes)
+        .find_map(|node| match node {
+            Node::Group(nested) if nested.source.is_none() && inside(nested, position) => {
+                Some(group_at(nested, position).unwrap_or(nested))
+            }
+            _ => None,
+        })
+}
+
+/// Apply any lock on `group` and the groups nested within it, recording the spans of those
+/// locked.
+fn apply_lock(group: &mut Group, locks: &[Lock], locked: &mut Vec<Span>) {
+    let lock = locks.iter().find(|lock| lock.start == group.span.start);
+    if let Some(lock) = lock.filter(|lock| lock.option < group.options.len()) {
+        group.locked = Some(lock.option);
+        locked.push(group.span.clone());
+    }
+    apply_locks(group, locks, locked);
+}
+
+/// Apply any locks on the groups nested within `group`.
+fn apply_locks(group: &mut Group, locks: &[Lock], locked: &mut Vec<Span>) {
+    for node in group
+        .options
+        .iter_mut()
+        .flat_map(|choice| &mut choice.nodes)
+    {
+        if let Node::Group(nested) = node {
+            if nested.source.is_none() {
+                apply_lock(nested, locks, locked);
+            }
+        }
+    }
+}
+
+/// `text` cut or padded to exactly `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted: String = text.chars().take(width).collect();
+    let length = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - length));
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample::ChoiceGuidance;
+
+    fn editor(text: &str, options: GenerationOptions) -> Editor {
+        Editor::new(
+            text.to_string(),
+            None,
+            ParseOptions::default(),
+            options,
+            4,
+            0,
+        )
+    }
+
+    fn prompts(editor: &Editor) -> Vec<&str> {
+        match &editor.preview {
+            Preview::Prompts { prompts, .. } => prompts.iter().map(|p| p.text.as_str()).collect(),
+            Preview::Errors(_) => panic!("template failed to parse"),
+        }
+    }
+
+    #[test]
+    fn group_at_finds_the_innermost_group() {
+        let template = Template::parse("a {b|{c|d}} e", &ParseOptions::default()).unwrap();
+        let root = template.root();
+        assert!(group_at(root, 1).is_none());
+        assert_eq!(group_at(root, 3).unwrap().span, 2..11);
+        assert_eq!(group_at(root, 7).unwrap().span, 5..10);
+        // On a brace is outside of the group it belongs to
+        assert_eq!(group_at(root, 5).unwrap().span, 2..11);
+        assert!(group_at(root, 11).is_none());
+    }
+
+    #[test]
+    fn apply_lock_fixes_nested_groups() {
+        let mut template = Template::parse("{a|{b|c}}", &ParseOptions::default()).unwrap();
+        let locks = [
+            Lock {
+                start: 3,
+                option: 1,
+            },
+            // Out of range, so ignored
+            Lock {
+                start: 0,
+                option: 5,
+            },
+        ];
+        let mut locked = Vec::new();
+        apply_locks(&mut template.root, &locks, &mut locked);
+        assert_eq!(locked, vec![Span { start: 3, end: 8 }]);
+        let Node::Group(outer) = &template.root.options[0].nodes[0] else {
+            panic!("expected a group");
+        };
+        assert_eq!(outer.locked, None);
+        let Node::Group(inner) = &outer.options[1].nodes[0] else {
+            panic!("expected a group");
+        };
+        assert_eq!(inner.locked, Some(1));
+    }
+
+    #[test]
+    fn locks_hold_with_any_guidance() {
+        for guidance in [
+            ChoiceGuidance::Random,
+            ChoiceGuidance::Shortest,
+            ChoiceGuidance::Longest,
+            ChoiceGuidance::LeastLikely,
+            ChoiceGuidance::MostLikely,
+            ChoiceGuidance::Sequential,
+            ChoiceGuidance::RoundRobin,
+            ChoiceGuidance::Stratified,
+        ] {
+            let options = GenerationOptions {
+                choice_guidance: Some(guidance.clone()),
+                ..GenerationOptions::default()
+            };
+            let mut editor = editor("{a:0|bbb:0|c:1} {x|y:0}", options);
+            editor.locks = vec![
+                Lock {
+                    start: 0,
+                    option: 0,
+                },
+                Lock {
+                    start: 16,
+                    option: 1,
+                },
+            ];
+            editor.refresh();
+            assert_eq!(prompts(&editor), ["a y"; 4], "{guidance:?}");
+        }
+    }
+
+    #[test]
+    fn stale_locks_are_forgotten() {
+        let mut editor = editor("{a|b}", GenerationOptions::default());
+        editor.locks = vec![Lock {
+            start: 1,
+            option: 0,
+        }];
+        editor.refresh();
+        assert!(editor.locks.is_empty());
+    }
+
+    #[test]
+    fn rows_wrap_by_characters() {
+        let mut editor = editor("abcdé\n\nxy", GenerationOptions::default());
+        editor.width = 2;
+        let rows: Vec<_> = editor
+            .rows()
+            .iter()
+            .map(|row| (row.start, row.end, row.last))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (0, 2, false),
+                (2, 4, false),
+                (4, 6, true),
+                (7, 7, true),
+                (8, 10, true)
+            ]
+        );
+        editor.cursor = 2;
+        assert_eq!(editor.cursor_position(&editor.rows()), (1, 0));
+        editor.cursor = 6;
+        assert_eq!(editor.cursor_position(&editor.rows()), (2, 1));
+    }
+}
@@ -0,0 +1,203 @@
+//! Simple utility for randomly generating prompts from a template.
+//!
+//! Templates take the form `a random {prompt|word}`; each pair of curly braces is a group of
+//! options separated by pipes, one of which is chosen at random every time a prompt is generated.
+//! Groups may be nested, and options may be weighted with a trailing `:weight`. A group can be
+//! bound to a variable with `{$name=...}`, and every `$name` in the template then repeats
+//! whichever option was chosen for it.
+//!
+//! Templates are parsed once into a tree of [`Group`]s, which can then be sampled any number of
+//! times without re-reading the source.
+//!
+//! ```
+//! use promptifier::{seeded_rng, GenerationOptions, ParseOptions, Template};
+//!
+//! let template = Template::parse("a {red|blue} {ball|box:3}", &ParseOptions::default()).unwrap();
+//! let mut rng = seeded_rng(0);
+//! let prompt = template.generate(&mut rng, &GenerationOptions::default());
+//! assert!(prompt.starts_with("a "));
+//! ```
+
+pub mod ast;
+pub mod diagnostic;
+pub mod enumerate;
+#[cfg(feature = "interactive")]
+pub mod interactive;
+pub mod lint;
+pub mod normalize;
+pub mod output;
+pub mod parse;
+pub mod prompt;
+pub mod rng;
+pub mod sample;
+pub mod split;
+pub mod stats;
+pub mod unique;
+
+pub use ast::{Choice, Group, Node, Selection, Span, Variable};
+pub use diagnostic::{line_column, Diagnostic, ParseErrors, Severity, Source};
+pub use enumerate::{Combinations, TooManyCombinations};
+pub use lint::{error_diagnostics, Lint, LintKind, Lints};
+pub use normalize::Normalization;
+pub use output::{Columns, Existing, FileNaming, Format, OutputFiles, Record, Writer};
+pub use parse::{
+    escape, parse_range, parse_weight, unescape, ParseError, ParseErrorKind, ParseOptions,
+    ParseWeightError, ParseWeightErrorKind, NEGATIVE_SEPARATOR,
+};
+pub use prompt::{Pick, Prompt};
+pub use rng::{prompt_seed, seeded_rng, PromptRng, SamplingAlgorithm};
+pub use sample::{Batch, ChoiceGuidance, GenerationOptions, Sampler};
+pub use split::{distribute, parse_all, ranges, Split};
+pub use stats::{GroupStats, OptionStats, Stats};
+pub use unique::{Unique, UniqueError};
+
+use num_bigint::BigUint;
+use rand::RngCore;
+
+/// A parsed prompt template, from which any number of prompts can be generated.
+#[derive(Clone, Debug)]
+pub struct Template {
+    pub(crate) root: Group,
+    /// Group making up the negative prompt, if the template has a negative section.
+    pub(crate) negative: Option<Group>,
+    pub(crate) variables: Vec<Variable>,
+    pub(crate) sources: Vec<Source>,
+}
+
+impl Template {
+    pub fn parse(source: &str, options: &ParseOptions) -> Result<Self, ParseErrors> {
+        parse::parse(source, options)
+    }
+
+    /// The top level group of options making up the template.
+    pub fn root(&self) -> &Group {
+        &self.root
+    }
+
+    /// The top level group of the template's negative prompt, written after a
+    /// `---negative---` line, if it has one.
+    pub fn negative(&self) -> Option<&Group> {
+        self.negative.as_ref()
+    }
+
+    /// The top level groups of the positive and, if there is one, negative prompt.
+    pub(crate) fn roots(&self) -> impl Iterator<Item = &Group> {
+        std::iter::once(&self.root).chain(&self.negative)
+    }
+
+    /// Whether prompts generated from this template with `options` depend on the others in
+    /// their batch, through guidance that spreads choices over it. Such prompts can't be
+    /// regenerated from their seed alone.
+    pub fn is_batched(&self, options: &GenerationOptions) -> bool {
+        self.roots()
+            .chain(self.variables.iter().map(|variable| &variable.group))
+            .flat_map(Group::nested)
+            .filter_map(|group| group.guidance.as_ref().or(options.choice_guidance.as_ref()))
+            .any(ChoiceGuidance::is_batched)
+    }
+
+    /// The template's variables; [`Node::Variable`] indexes into this.
+    pub fn variables(&self) -> &[Variable] {
+        &self.variables
+    }
+
+    /// The text the template was parsed from, followed by any wildcard or included files it
+    /// loaded. Spans refer into one of these.
+    pub fn sources(&self) -> &[Source] {
+        &self.sources
+    }
+
+    /// Number of distinct combinations of choices this template can produce.
+    pub fn count(&self) -> BigUint {
+        stats::count(self)
+    }
+
+    /// Relative share of prompts the template gets when several templates divide a batch
+    /// between them: the total weight of its top level options.
+    pub fn weight(&self) -> f64 {
+        self.root.options.iter().map(|choice| choice.weight).sum()
+    }
+
+    /// Size and probability distribution of the space of prompts this template can produce
+    /// with `options`, taking their temperature into account.
+    pub fn stats(&self, options: &GenerationOptions) -> Stats {
+        stats::stats(self, options)
+    }
+
+    /// Constructs in the template that are valid, but probably not what was intended.
+    pub fn lint(&self) -> Lints {
+        lint::lint(self)
+    }
+
+    /// Iterate over every combination of choices this template can produce, in a
+    /// deterministic order. Fails up front if there are more than `limit` combinations.
+    pub fn combinations(
+        &self,
+        limit: Option<u64>,
+    ) -> Result<Combinations<'_>, TooManyCombinations> {
+        if let Some(limit) = limit {
+            let count = self.count();
+            if count > BigUint::from(limit) {
+                return Err(TooManyCombinations { count, limit });
+            }
+        }
+        Ok(Combinations::new(self))
+    }
+
+    /// Generate a single prompt from this template.
+    pub fn generate<R: RngCore + ?Sized>(
+        &self,
+        rng: &mut R,
+        options: &GenerationOptions,
+    ) -> String {
+        Sampler::new(self, rng, options).generate()
+    }
+
+    /// Generate a single prompt from this template, along with the choices that produced it.
+    pub fn generate_prompt<R: RngCore + ?Sized>(
+        &self,
+        rng: &mut R,
+        options: &GenerationOptions,
+    ) -> Prompt {
+        Sampler::new(self, rng, options).generate_prompt()
+    }
+
+    /// Generate a single prompt from `seed`. The same template, seed and options always
+    /// produce the same prompt, across releases as well as runs; see [`rng`].
+    pub fn generate_seeded(&self, seed: u64, options: &GenerationOptions) -> Prompt {
+        let mut rng = seeded_rng(seed);
+        Prompt {
+            seed: Some(seed),
+            ..self.generate_prompt(&mut rng, options)
+        }
+    }
+
+    /// Generate a single prompt from `seed` as part of `batch`, carrying on from the prompts
+    /// generated in it before, for guidance that spreads choices over a batch.
+    pub fn generate_in_batch(
+        &self,
+        seed: u64,
+        options: &GenerationOptions,
+        batch: &mut Batch,
+    ) -> Prompt {
+        let mut rng = seeded_rng(seed);
+        Prompt {
+            seed: Some(seed),
+            ..Sampler::new(self, &mut rng, options)
+                .with_batch(batch)
+                .generate_prompt()
+        }
+    }
+
+    /// Generate `num` prompts from this template, none of which are the same, seeding each
+    /// attempt with [`prompt_seed`]. See [`Unique`].
+    pub fn generate_unique<'a>(
+        &'a self,
+        batch_seed: u64,
+        options: &'a GenerationOptions,
+        num: usize,
+        enumeration_limit: Option<u64>,
+    ) -> Result<Unique<'a>, UniqueError> {
+        Unique::new(self, batch_seed, options, num, enumeration_limit)
+    }
+}
@@ -0,0 +1,269 @@
+//! Warnings about template constructs that parse fine but are probably mistakes.
+
+use crate::ast::{Choice, Group, Node, Span};
+use crate::diagnostic::{display_all, Diagnostic, ParseErrors, Severity, Source};
+use crate::parse::{escape, ParseErrorKind};
+use crate::stats::label;
+use crate::Template;
+use std::collections::HashSet;
+use std::fmt;
+use thiserror::Error;
+
+/// A suspicious construct, located by a byte span in one of the template's sources.
+#[derive(Clone, Debug, Error)]
+#[error("{kind}")]
+pub struct Lint {
+    pub kind: LintKind,
+    /// Index of the source the lint applies to, within [`Lints::sources`].
+    pub source_index: usize,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, Error)]
+pub enum LintKind {
+    #[error("Empty option between two others; is there a stray '|'?")]
+    EmptyOption,
+    #[error("Option '{0}' has a weight of zero and is never chosen")]
+    ZeroWeight(String),
+    #[error("Group has a single option, so it always produces the same text")]
+    SingleOption,
+    #[error("Option '{0}' appears more than once in the group")]
+    DuplicateOption(String),
+    #[error("Every option in the group has a weight of zero, so only the last one is ever chosen")]
+    AllZeroWeights,
+    #[error(
+        "'{0}' looks like diffusion emphasis syntax, and is kept in the prompt as literal text \
+         rather than weighting the option"
+    )]
+    EmphasisWeight(String),
+    #[error(
+        "'{0}' looks like diffusion emphasis syntax, but is read as an option with an invalid \
+         weight unless invalid weight literals are ignored"
+    )]
+    InvalidEmphasisWeight(String),
+}
+
+impl LintKind {
+    /// Short identifier for the kind of lint, for tools consuming diagnostics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LintKind::EmptyOption => "empty_option",
+            LintKind::ZeroWeight(_) => "zero_weight",
+            LintKind::SingleOption => "single_option",
+            LintKind::DuplicateOption(_) => "duplicate_option",
+            LintKind::AllZeroWeights => "all_zero_weights",
+            LintKind::EmphasisWeight(_) | LintKind::InvalidEmphasisWeight(_) => "emphasis_weight",
+        }
+    }
+}
+
+/// Every lint found in a template, along with the sources they apply to. Displays as a list
+/// of warnings, each quoting the offending line.
+#[derive(Clone, Debug)]
+pub struct Lints {
+    pub lints: Vec<Lint>,
+    pub sources: Vec<Source>,
+}
+
+impl Lints {
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.lints
+            .iter()
+            .map(|lint| {
+                Diagnostic::new(
+                    Severity::Warning,
+                    lint.kind.code(),
+                    lint.to_string(),
+                    &self.sources[lint.source_index],
+                    &lint.span,
+                )
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Lints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        display_all(f, &self.diagnostics(), "warning")
+    }
+}
+
+/// Find the `(text:1.2)` emphasis expressions in `text`, as byte ranges.
+fn emphases(text: &str) -> impl Iterator<Item = Span> + '_ {
+    text.match_indices('(').filter_map(|(start, _)| {
+        let inner = &text[start + 1..];
+        let end = inner.find(['(', ')'])?;
+        if !inner[end..].starts_with(')') {
+            return None;
+        }
+        let (_, weight) = inner[..end].rsplit_once(':')?;
+        weight.trim().parse::<f64>().ok()?;
+        Some(start..start + end + 2)
+    })
+}
+
+/// A representation of an option's contents that is equal for options that always expand to
+/// the same text, regardless of where they appear.
+fn canonical(choice: &Choice) -> String {
+    choice
+        .nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(text) => escape(text),
+            Node::Group(group) => match (&group.wildcard, &group.include) {
+                (Some(name), _) => format!("__{name}__"),
+                (_, Some(path)) => format!("{{@{path}}}"),
+                _ => {
+                    let options: Vec<_> = group
+                        .options
+                        .iter()
+                        .map(|choice| format!("{}:{}", canonical(choice), choice.weight))
+                        .collect();
+                    format!("{{{}}}", options.join("|"))
+                }
+            },
+            Node::Variable(index) => format!("${index}"),
+        })
+        .collect()
+}
+
+struct Linter<'a> {
+    template: &'a Template,
+    /// Sources already linted; a wildcard used several times only needs linting once.
+    linted_sources: HashSet<usize>,
+    lints: Vec<Lint>,
+}
+
+impl<'a> Linter<'a> {
+    fn lint(&mut self, kind: LintKind, source_index: usize, span: Span) {
+        self.lints.push(Lint {
+            kind,
+            source_index,
+            span,
+        });
+    }
+
+    /// Lint `group`, which appears in the source `source_index`. Single option groups are
+    /// only reported if `nested` is set, since the template and variables are groups too.
+    fn group(&mut self, group: &'a Group, source_index: usize, nested: bool) {
+        let options_source = match group.source {
+            Some(source) => {
+                if !self.linted_sources.insert(source) {
+                    return;
+                }
+                source
+            }
+            None => source_index,
+        };
+        if nested && group.source.is_none() && group.options.len() == 1 {
+            self.lint(LintKind::SingleOption, source_index, group.span.clone());
+        }
+        if group.options.len() > 1 && group.options.iter().all(|choice| choice.weight == 0.0) {
+            self.lint(LintKind::AllZeroWeights, source_index, group.span.clone());
+        } else {
+            for choice in &group.options {
+                if choice.weight == 0.0 {
+                    let label = label(choice, self.template);
+                    self.lint(
+                        LintKind::ZeroWeight(label),
+                        options_source,
+                        choice.span.clone(),
+                    );
+                }
+            }
+        }
+        let last = group.options.len() - 1;
+        let mut seen = HashSet::new();
+        for (index, choice) in group.options.iter().enumerate() {
+            if choice.nodes.is_empty() && index != 0 && index != last {
+                self.lint(LintKind::EmptyOption, options_source, choice.span.clone());
+            } else if !seen.insert(canonical(choice)) {
+                let label = label(choice, self.template);
+                let kind = LintKind::DuplicateOption(label);
+                self.lint(kind, options_source, choice.span.clone());
+            }
+            for node in &choice.nodes {
+                match node {
+                    Node::Text(text) => {
+                        if let Some(emphasis) = emphases(text).next() {
+                            let emphasis = &text[emphasis];
+                            // Found in the unescaped text, so look for it in the source to
+                            // point at it, falling back on the whole option
+                            let source = &self.template.sources[options_source].text;
+                            let span = source[choice.span.clone()].find(emphasis).map_or(
+                                choice.span.clone(),
+                                |offset| {
+                                    let start = choice.span.start + offset;
+                                    start..start + emphasis.len()
+                                },
+                            );
+                            let kind = LintKind::EmphasisWeight(emphasis.to_string());
+                            self.lint(kind, options_source, span);
+                        }
+                    }
+                    Node::Group(group) => self.group(group, options_source, true),
+                    Node::Variable(_) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Find every suspicious construct in `template`, in the order they appear in each source.
+pub fn lint(template: &Template) -> Lints {
+    let mut linter = Linter {
+        template,
+        linted_sources: HashSet::new(),
+        lints: Vec::new(),
+    };
+    for root in template.roots() {
+        linter.group(root, 0, false);
+    }
+    for variable in &template.variables {
+        linter.group(&variable.group, variable.source_index, false);
+    }
+    let mut lints = linter.lints;
+    lints.sort_by_key(|lint| (lint.source_index, lint.span.start));
+    Lints {
+        lints,
+        sources: template.sources.clone(),
+    }
+}
+
+/// Diagnostics for `errors`, except that an invalid weight specifier which closes a
+/// `(text:1.2)` emphasis expression is reported on the whole expression, with the
+/// [`LintKind::InvalidEmphasisWeight`] hint added to the message, since it was most likely
+/// never meant as a weight.
+pub fn error_diagnostics(errors: &ParseErrors) -> Vec<Diagnostic> {
+    errors
+        .errors
+        .iter()
+        .zip(errors.diagnostics())
+        .map(|(error, diagnostic)| {
+            let ParseErrorKind::InvalidWeightSpecifier(_) = error.kind else {
+                return diagnostic;
+            };
+            let source = &errors.sources[error.source_index];
+            let line_start = source.text[..error.span.start]
+                .rfind('\n')
+                .map_or(0, |index| index + 1);
+            let line = source.text[line_start..].lines().next().unwrap_or("");
+            let emphasis = emphases(line)
+                .map(|span| line_start + span.start..line_start + span.end)
+                .find(|span| span.contains(&error.span.start));
+            match emphasis {
+                Some(span) => {
+                    let kind = LintKind::InvalidEmphasisWeight(source.text[span.clone()].into());
+                    Diagnostic::new(
+                        Severity::Error,
+                        kind.code(),
+                        format!("{}; {kind}", diagnostic.message),
+                        source,
+                        &span,
+                    )
+                }
+                None => diagnostic,
+            }
+        })
+        .collect()
+}
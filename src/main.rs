@@ -1,248 +1,101 @@
-use clap::{Parser, ValueEnum};
-use rand::prelude::*;
-use serde::Serialize;
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(feature = "interactive")]
+use promptifier::interactive::Editor;
+use promptifier::{
+    line_column, Batch, ChoiceGuidance, Columns, Diagnostic, Existing, FileNaming, Format,
+    GenerationOptions, Normalization, OutputFiles, ParseOptions, Prompt, Record, SamplingAlgorithm,
+    Split, Stats, Template, UniqueError,
+};
+use rand::random;
+use std::collections::HashSet;
 use std::fs;
-use std::io::Write;
-use std::iter::once;
-use std::num::ParseFloatError;
-use std::{fs::File, path::PathBuf};
-use thiserror::Error;
-
-#[derive(Clone, Debug, Error)]
-enum ParseError {
-    #[error("Unexpected closing brace at char {0}")]
-    UnexpectedClosingBrace(usize),
-    #[error("Unclosed open brace at char {0}")]
-    UnclosedBrace(usize),
-    #[error("Invalid weight specifier at char {0}: {1}")]
-    InvalidWeightSpecifier(usize, ParseWeightError),
-}
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-#[derive(Clone, Debug)]
-struct Choice {
-    text: String,
-    weight: f64,
-}
+/// Simple utility for generating prompts from a random template.
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-impl Choice {
-    fn new() -> Self {
-        Self {
-            text: String::new(),
-            weight: 1.0,
-        }
-    }
-}
+    /// Source prompt to parse. If neither this nor --input-file is given, the prompt is read
+    /// from standard input when it isn't a terminal
+    prompt: Option<String>,
 
-#[derive(Clone, Debug)]
-struct Frame {
-    start_index: usize,
-    choices: Vec<Choice>,
-    top: Choice,
-}
+    /// File to take source prompt from, or `-` for standard input
+    #[clap(short, long)]
+    input_file: Option<PathBuf>,
 
-impl Frame {
-    fn new(start_index: usize) -> Self {
-        Self {
-            start_index,
-            choices: Vec::new(),
-            top: Choice::new(),
-        }
-    }
+    /// Treat the input as several templates, one per line or per blank line separated block,
+    /// each generating --num prompts
+    #[clap(long, value_enum)]
+    split: Option<Split>,
 
-    fn push(&mut self, choice: Choice) {
-        self.choices.push(std::mem::replace(&mut self.top, choice));
-    }
+    /// With --split, divide --num prompts between the templates in proportion to their weights
+    /// instead. A template's weight is that of its top level option, as in `a {red|blue} ball:3`
+    #[clap(long, action, requires = "split")]
+    distribute: bool,
 
-    fn choose(self, rng: &mut StdRng, guidance: &Option<ChoiceGuidance>) -> Choice {
-        match guidance {
-            None => {
-                let weight_sum =
-                    self.choices.iter().map(|c| c.weight).sum::<f64>() + self.top.weight;
-                let weighted_index: f64 = rng.gen();
-                let mut weighted_index = weighted_index * weight_sum;
-                for choice in self.choices {
-                    if weighted_index <= choice.weight {
-                        return choice;
-                    }
-                    weighted_index -= choice.weight;
-                }
-                self.top
-            }
-            Some(guidance) => {
-                let mut options: Vec<_> = self.choices.into_iter().chain(once(self.top)).collect();
-                match guidance {
-                    ChoiceGuidance::Longest | ChoiceGuidance::Shortest => {
-                        options.sort_by_key(|c| c.text.len())
-                    }
-                    ChoiceGuidance::MostLikely | ChoiceGuidance::LeastLikely => {
-                        options.sort_by(|a, b| {
-                            a.weight
-                                .partial_cmp(&b.weight)
-                                .unwrap_or(std::cmp::Ordering::Equal)
-                        })
-                    }
-                }
-                match guidance {
-                    ChoiceGuidance::Longest | ChoiceGuidance::MostLikely => options.pop().unwrap(),
-                    ChoiceGuidance::Shortest | ChoiceGuidance::LeastLikely => {
-                        options.swap_remove(0)
-                    }
-                }
-            }
-        }
-    }
-}
+    /// With --split, only generate from the template at this position, counting from 0
+    #[clap(long, requires = "split")]
+    template: Option<usize>,
 
-struct Stack {
-    stack: Vec<Frame>,
-    top: Frame,
-}
-
-impl Stack {
-    fn new() -> Self {
-        Self {
-            stack: Vec::new(),
-            top: Frame::new(0),
-        }
-    }
+    /// Directory to look up `__name__` wildcards in; `__name__` expands to a random line from
+    /// `<WILDCARD_DIR>/name.txt`
+    #[clap(short, long)]
+    wildcard_dir: Option<PathBuf>,
 
-    fn push(&mut self, start_index: usize) {
-        self.stack
-            .push(std::mem::replace(&mut self.top, Frame::new(start_index)));
-    }
+    /// Number of prompts to generate
+    #[clap(short, long, default_value_t = 1)]
+    num: usize,
 
-    fn pop(&mut self) -> Option<Frame> {
-        self.stack
-            .pop()
-            .map(|frame| std::mem::replace(&mut self.top, frame))
-    }
-}
+    /// Generate every possible combination of choices once, in a fixed order, instead of
+    /// --num random prompts
+    #[clap(short = 'x', long, action)]
+    exhaustive: bool,
 
-#[derive(Clone, Debug, Error)]
-#[error("'{specifier}': {parse_error}")]
-struct ParseWeightError {
-    specifier: String,
-    index: usize,
-    parse_error: ParseWeightErrorKind,
-}
+    /// Never output the same prompt twice. Fails if the template can't produce --num distinct
+    /// prompts; with --exhaustive, skips combinations that produce an earlier prompt
+    #[clap(short, long, action)]
+    unique: bool,
 
-#[derive(Clone, Debug, Error)]
-enum ParseWeightErrorKind {
-    #[error("{0}")]
-    FloatParse(#[from] ParseFloatError),
-    #[error("Weights cannot be negative")]
-    NegativeWeight,
-}
+    /// Abort --exhaustive or --unique generation if the template has more combinations than
+    /// this
+    #[clap(long, default_value_t = 1_000_000)]
+    max_combinations: u64,
 
-fn parse_weight(
-    maybe_weighted: &str,
-    ignore_invalid_weight_literals: bool,
-) -> Result<(&str, f64), ParseWeightError> {
-    let Some((text, weight_text)) = maybe_weighted.rsplit_once(":") else {
-        return Ok((maybe_weighted, 1.0));
-    };
-    let maybe_weight = weight_text.parse().map_err(|parse_error| ParseWeightError {
-        specifier: weight_text.to_string(),
-        index: text.len() + 1,
-        parse_error: ParseWeightErrorKind::FloatParse(parse_error),
-    });
-    match maybe_weight {
-        Ok(weight) if weight >= 0.0 => Ok((text, weight)),
-        Ok(_) => Err(ParseWeightError {
-            specifier: weight_text.to_string(),
-            index: text.len() + 1,
-            parse_error: ParseWeightErrorKind::NegativeWeight,
-        }),
-        _ if ignore_invalid_weight_literals => Ok((maybe_weighted, 1.0)),
-        Err(err) => Err(err),
-    }
-}
-#[derive(ValueEnum, Clone, Debug, Serialize)]
-enum ChoiceGuidance {
-    Shortest,
-    Longest,
-    LeastLikely,
-    MostLikely,
-}
+    /// Print the number of combinations the template can produce and how likely each choice
+    /// is, instead of generating prompts
+    #[clap(long, action)]
+    stats: bool,
 
-struct GenerationOptions {
-    choice_guidance: Option<ChoiceGuidance>,
-    ignore_invalid_weight_literals: bool,
-}
+    /// Output file, or `-` for standard output
+    #[clap(short, long, default_value = "prompts.txt")]
+    out: PathBuf,
 
-fn generate(
-    mut prompt: &str,
-    rng: &mut StdRng,
-    options: &GenerationOptions,
-) -> Result<String, ParseError> {
-    let mut stack = Stack::new();
-    let mut global_index = 0;
-    let parse_weight_and_apply = |text, stack: &mut Stack, global_index| {
-        let (text, weight) = parse_weight(text, options.ignore_invalid_weight_literals)
-            .map_err(|err| ParseError::InvalidWeightSpecifier(global_index + err.index, err))?;
-        stack.top.top.text.push_str(text);
-        stack.top.top.weight = weight;
-        Ok(())
-    };
-    loop {
-        match prompt.find(&['|', '{', '}']) {
-            None => break,
-            Some(index) => {
-                global_index += index;
-                let pre = &prompt[..index];
-                let post = &prompt[index..];
-                match post.chars().next() {
-                    None => break,
-                    Some('|') => {
-                        parse_weight_and_apply(pre, &mut stack, global_index)?;
-                        stack.top.push(Choice::new());
-                    }
-                    Some('{') => {
-                        stack.top.top.text.push_str(pre);
-                        stack.push(global_index);
-                    }
-                    Some('}') => {
-                        parse_weight_and_apply(pre, &mut stack, global_index)?;
-                        match stack.pop() {
-                            None => return Err(ParseError::UnexpectedClosingBrace(global_index)),
-                            Some(frame) => stack
-                                .top
-                                .top
-                                .text
-                                .push_str(&frame.choose(rng, &options.choice_guidance).text),
-                        }
-                    }
-                    _ => unreachable!(),
-                }
-                prompt = &post[1..];
-            }
-        }
-    }
-    parse_weight_and_apply(prompt, &mut stack, global_index)?;
-    if !stack.stack.is_empty() {
-        Err(ParseError::UnclosedBrace(stack.top.start_index))
-    } else {
-        Ok(stack.top.choose(rng, &options.choice_guidance).text)
-    }
-}
+    /// Add the generated prompts to the end of the output file if it already exists
+    #[clap(short, long, action, conflicts_with = "per_file")]
+    append: bool,
 
-/// Simple utility for generating prompts from a random template.
-#[derive(Parser)]
-struct Args {
-    /// Source prompt to parse
-    prompt: Option<String>,
+    /// Overwrite the output file if it already exists, rather than refusing to
+    #[clap(long, action, conflicts_with = "append")]
+    force: bool,
 
-    /// File to take source prompt from
-    #[clap(short, long)]
-    input_file: Option<PathBuf>,
+    /// Write to a new file named after --out on every run, rather than to --out itself
+    #[clap(long, value_enum)]
+    new_file: Option<FileNaming>,
 
-    /// Number of prompts to generate
-    #[clap(short, long, default_value_t = 1)]
-    num: usize,
+    /// Split the output into several files of at most this many prompts each, named after
+    /// --out with `-part1`, `-part2` and so on
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    per_file: Option<u64>,
 
-    /// Output file
-    #[clap(short, long, default_value = "prompts.txt")]
-    out: PathBuf,
+    /// Format to write the output file in; structured formats record the seed and the choices
+    /// that produced each prompt
+    #[clap(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
 
     /// Print generated prompts to console
     #[clap(short, long, action)]
@@ -256,57 +109,539 @@ struct Args {
     #[clap(short = 'g', long)]
     choice_guidance: Option<ChoiceGuidance>,
 
-    /// Ignore improperly formatted weights and interpret the full text, including the malformed 
+    /// Reshape the weights of every group's options: 0 only ever picks the most likely, 1 uses
+    /// the weights as written, and higher values even them out towards a uniform choice
+    #[clap(short, long, value_parser = parse_temperature)]
+    temperature: Option<f64>,
+
+    /// Ignore improperly formatted weights and interpret the full text, including the malformed
     /// weight specifier, as a choice with a weight of 1.
     /// Useful when combining with emphasis syntax common in diffusion UIs. Does not ignore
     /// errors produced from negative weights.
     #[clap(short = 'e', long, action)]
     ignore_invalid_weight_literals: bool,
 
+    /// Clean up generated prompts: collapse repeated whitespace, trim every line, and tidy the
+    /// --separators that empty options leave repeated or dangling
+    #[clap(short = 'N', long, action)]
+    normalize: bool,
+
+    /// Separator characters for --normalize to tidy, such as `,;`; pass an empty list to only
+    /// normalize whitespace
+    #[clap(long, default_value = ",;", requires = "normalize")]
+    separators: String,
+
+    /// Version of the algorithm used to make random choices. Recorded seeds only regenerate the
+    /// same prompts under the version they were generated with
+    #[clap(long, value_enum, default_value_t = SamplingAlgorithm::default())]
+    sampling_algorithm: SamplingAlgorithm,
+
     /// Seed for the random number generator. Pass the same seed to get the same set of prompts.
+    /// Each prompt in the batch is generated from its own seed, derived from this one, which
+    /// structured output formats record
     #[clap(short, long)]
     seed: Option<u64>,
+
+    /// Regenerate the single prompt recorded with this per-prompt seed, rather than a batch
+    #[clap(short, long, conflicts_with_all = ["seed", "num", "exhaustive", "unique"])]
+    prompt_seed: Option<u64>,
 }
 
-fn main() {
-    let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Subcommand)]
+enum Command {
+    /// Parse a template without generating prompts, reporting any errors along with warnings
+    /// about constructs that are probably mistakes
+    Check {
+        /// Template file to check, or `-` for standard input
+        template: PathBuf,
+
+        /// Directory to look up `__name__` wildcards in
+        #[clap(short, long)]
+        wildcard_dir: Option<PathBuf>,
+
+        /// Ignore improperly formatted weights, as when generating
+        #[clap(short = 'e', long, action)]
+        ignore_invalid_weight_literals: bool,
+
+        /// Format to report problems in; `json` prints an array of diagnostics for editor
+        /// integrations
+        #[clap(short, long, value_enum, default_value_t = CheckFormat::Text)]
+        format: CheckFormat,
+    },
+
+    /// Edit a template in the terminal with a live preview of the prompts it generates,
+    /// highlighting errors as you type
+    #[cfg(feature = "interactive")]
+    Edit {
+        /// Template file to edit, created on saving if it doesn't exist. Without one, the
+        /// template is printed on quitting
+        template: Option<PathBuf>,
+
+        /// Number of prompts to preview
+        #[clap(short, long, default_value_t = 5)]
+        num: usize,
+
+        /// Directory to look up `__name__` wildcards in
+        #[clap(short, long)]
+        wildcard_dir: Option<PathBuf>,
+
+        /// Ignore improperly formatted weights, as when generating
+        #[clap(short = 'e', long, action)]
+        ignore_invalid_weight_literals: bool,
+
+        /// Guidance heuristic to preview with, as when generating
+        #[clap(short = 'g', long)]
+        choice_guidance: Option<ChoiceGuidance>,
+
+        /// Temperature to preview with, as when generating
+        #[clap(short, long, value_parser = parse_temperature)]
+        temperature: Option<f64>,
+
+        /// Normalize the previewed prompts, as when generating
+        #[clap(short = 'N', long, action)]
+        normalize: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CheckFormat {
+    Text,
+    Json,
+}
+
+/// Parse a --temperature, which can be anything from 0 up.
+fn parse_temperature(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(temperature) if temperature >= 0.0 => Ok(temperature),
+        Ok(_) => Err("temperature cannot be negative".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Suggest how to get past an output file that already exists, mentioning --append only if
+/// it can be used.
+fn output_error(err: io::Error, can_append: bool) -> Box<dyn std::error::Error> {
+    match err.kind() {
+        io::ErrorKind::AlreadyExists if can_append => {
+            format!("{err}; pass --force to overwrite it or --append to add to it").into()
+        }
+        io::ErrorKind::AlreadyExists => format!("{err}; pass --force to overwrite it").into(),
+        _ => err.into(),
+    }
+}
+
+/// Whether `path` is `-`, standing for standard input or output.
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Name to refer to the template read from `file` by in diagnostics.
+fn source_name(file: &Path) -> String {
+    if is_stdio(file) {
+        "<stdin>".to_string()
+    } else {
+        file.display().to_string()
+    }
+}
+
+/// Read a template from `file`, or standard input for `-`, leaving off the line break it ends
+/// in, if any.
+fn read_template(file: &Path) -> io::Result<String> {
+    let mut text = if is_stdio(file) {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(file)?
+    };
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    Ok(text)
+}
+
+/// Edit the template in `file` interactively, or a new one if there's no file.
+#[cfg(feature = "interactive")]
+fn edit(
+    file: Option<PathBuf>,
+    parse_options: ParseOptions,
+    options: GenerationOptions,
+    num: usize,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        Err("edit needs an interactive terminal")?;
+    }
+    let text = match &file {
+        Some(file) if file.exists() => read_template(file)?,
+        _ => String::new(),
+    };
+    let editor = Editor::new(text, file.clone(), parse_options, options, num, random());
+    let text = editor.run()?;
+    if file.is_none() {
+        println!("{text}");
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Report the problems with the template in `file`, failing if it has any errors.
+fn check(
+    file: PathBuf,
+    options: ParseOptions,
+    format: CheckFormat,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let source = read_template(&file)?;
+    let options = ParseOptions {
+        source_name: Some(source_name(&file)),
+        source_path: (!is_stdio(&file)).then(|| file.clone()),
+        ..options
+    };
+    let (diagnostics, status): (Vec<Diagnostic>, _) = match Template::parse(&source, &options) {
+        Ok(template) => (template.lint().diagnostics(), ExitCode::SUCCESS),
+        Err(errors) => {
+            let mut diagnostics = promptifier::error_diagnostics(&errors);
+            for diagnostic in &mut diagnostics {
+                if diagnostic.code == "emphasis_weight" {
+                    diagnostic
+                        .message
+                        .push_str("; pass -e to keep it in the prompt as literal text");
+                }
+            }
+            (diagnostics, ExitCode::FAILURE)
+        }
+    };
+    match format {
+        CheckFormat::Text if diagnostics.is_empty() => println!("No problems found"),
+        CheckFormat::Text => {
+            for diagnostic in &diagnostics {
+                println!("{diagnostic}\n");
+            }
+            match diagnostics.len() {
+                1 => println!("1 problem found"),
+                count => println!("{count} problems found"),
+            }
+        }
+        CheckFormat::Json => println!("{}", serde_json::to_string_pretty(&diagnostics)?),
+    }
+    Ok(status)
+}
+
+fn percent(probability: f64) -> String {
+    let percent = probability * 100.0;
+    if percent == 0.0 || percent >= 0.0001 {
+        format!("{percent:.4}%")
+    } else {
+        format!("{percent:.3e}%")
+    }
+}
+
+fn print_stats(stats: &Stats, source: &str) {
+    let char_index = |byte: usize| source[..byte].chars().count();
+    println!("Combinations: {}", stats.combinations);
+    println!("Entropy: {:.2} bits", stats.entropy);
+    println!("Most likely prompt: {}", percent(stats.max_probability));
+    println!("Least likely prompt: {}", percent(stats.min_probability));
+    if stats.groups.is_empty() {
+        return;
+    }
+    println!("Groups:");
+    for group in &stats.groups {
+        let indent = "  ".repeat(group.depth + 1);
+        let location = match &group.file {
+            Some(file) => format!("in {file}"),
+            None => format!(
+                "chars {}..{}",
+                char_index(group.span.start),
+                char_index(group.span.end)
+            ),
+        };
+        let variable = match &group.variable {
+            Some(name) => format!("${name} "),
+            None => String::new(),
+        };
+        println!(
+            "{indent}{variable}{location}: {} options, {} combinations, {:.2} bits",
+            group.options.len(),
+            group.combinations,
+            group.entropy
+        );
+        for option in &group.options {
+            println!(
+                "{indent}  {:>9}  {}",
+                percent(option.probability),
+                option.label
+            );
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let result = (|| -> Result<ExitCode, Box<dyn std::error::Error>> {
         let Args {
+            command,
             prompt,
             input_file,
+            split,
+            distribute,
+            template,
+            wildcard_dir,
             num,
+            exhaustive,
+            unique,
+            max_combinations,
+            stats,
             out,
+            append,
+            force,
+            new_file,
+            per_file,
+            format,
             verbose,
             dry_run,
             choice_guidance,
+            temperature,
             ignore_invalid_weight_literals,
+            normalize,
+            separators,
+            sampling_algorithm,
             seed,
+            prompt_seed,
         } = Args::parse();
-        let prompt = match (prompt, input_file) {
-            (Some(prompt), _) => prompt,
-            (_, Some(file)) => fs::read_to_string(file)?,
+        match command {
+            Some(Command::Check {
+                template,
+                wildcard_dir,
+                ignore_invalid_weight_literals,
+                format,
+            }) => {
+                let options = ParseOptions {
+                    ignore_invalid_weight_literals,
+                    wildcard_dir,
+                    include_dir: template.parent().map(Path::to_path_buf),
+                    source_name: None,
+                    source_path: None,
+                };
+                return check(template, options, format);
+            }
+            #[cfg(feature = "interactive")]
+            Some(Command::Edit {
+                template,
+                num,
+                wildcard_dir,
+                ignore_invalid_weight_literals,
+                choice_guidance,
+                temperature,
+                normalize,
+            }) => {
+                let parse_options = ParseOptions {
+                    ignore_invalid_weight_literals,
+                    wildcard_dir,
+                    include_dir: template
+                        .as_deref()
+                        .and_then(Path::parent)
+                        .map(Path::to_path_buf),
+                    source_name: template.as_deref().map(source_name),
+                    source_path: template.clone(),
+                };
+                let options = GenerationOptions {
+                    choice_guidance,
+                    temperature,
+                    normalization: normalize.then(Normalization::default),
+                    ..GenerationOptions::default()
+                };
+                return edit(template, parse_options, options, num);
+            }
+            None => {}
+        }
+        let input_file = match (&prompt, input_file) {
+            (None, None) if !io::stdin().is_terminal() => Some(PathBuf::from("-")),
+            (_, input_file) => input_file,
+        };
+        let (prompt, source_name) = match (prompt, &input_file) {
+            (Some(prompt), _) => (prompt, None),
+            (_, Some(file)) => (read_template(file)?, Some(source_name(file))),
             _ => Err("No prompt source specified")?,
         };
-        let mut out = (!dry_run).then(|| File::create(out)).transpose()?;
-        let mut rng = match seed {
-            Some(seed) => StdRng::seed_from_u64(seed),
-            None => StdRng::from_entropy(),
+        let options = ParseOptions {
+            ignore_invalid_weight_literals,
+            wildcard_dir,
+            include_dir: input_file
+                .as_deref()
+                .and_then(Path::parent)
+                .map(Path::to_path_buf),
+            source_name,
+            source_path: input_file.filter(|file| !is_stdio(file)),
+        };
+        let templates = match split {
+            Some(split) => promptifier::parse_all(&prompt, split, &options)?,
+            None => vec![Template::parse(&prompt, &options)?],
         };
+        let count = templates.len();
+        let templates: Vec<_> = templates
+            .into_iter()
+            .enumerate()
+            .filter(|&(index, _)| template.is_none_or(|template| template == index))
+            .collect();
+        if let (Some(template), true) = (template, templates.is_empty()) {
+            Err(format!(
+                "No template {template}; the input only has {count} templates"
+            ))?;
+        }
         let options = GenerationOptions {
             choice_guidance,
-            ignore_invalid_weight_literals,
+            sampling_algorithm,
+            temperature,
+            normalization: normalize.then(|| Normalization {
+                whitespace: true,
+                separators: separators.chars().filter(|c| !c.is_whitespace()).collect(),
+            }),
         };
-        for _ in 0..num {
-            let prompt = generate(&prompt, &mut rng, &options)?;
-            if verbose {
-                println!("{prompt}");
+        if stats {
+            for (index, template) in &templates {
+                if split.is_some() {
+                    let (line, _) = line_column(&prompt, template.root().span.start);
+                    println!("Template {index} (line {line}):");
+                }
+                print_stats(&template.stats(&options), &prompt);
             }
-            if let Some(out) = &mut out {
-                writeln!(out, "{prompt}")?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        let batched = templates.iter().any(|(_, t)| t.is_batched(&options));
+        if prompt_seed.is_some() && batched {
+            Err("--prompt-seed can't regenerate prompts whose choices were spread over a batch")?;
+        }
+        if is_stdio(&out) && (new_file.is_some() || per_file.is_some()) {
+            Err("--new-file and --per-file need an output file, not standard output")?;
+        }
+        let existing = match (append, force) {
+            (true, _) => Existing::Append,
+            (_, true) => Existing::Overwrite,
+            _ => Existing::Refuse,
+        };
+        let columns = Columns {
+            templates: split.is_some(),
+            negatives: templates.iter().any(|(_, t)| t.negative().is_some()),
+        };
+        let counts = if distribute {
+            let weights: Vec<_> = templates
+                .iter()
+                .map(|(_, template)| template.weight())
+                .collect();
+            promptifier::distribute(num, &weights)
+        } else {
+            vec![num; templates.len()]
+        };
+        // An upper bound for --exhaustive, as combinations may turn out to be the same
+        let records = match (exhaustive, prompt_seed) {
+            (true, _) => templates
+                .iter()
+                .map(|(_, template)| {
+                    template
+                        .count()
+                        .min(max_combinations.into())
+                        .try_into()
+                        .unwrap_or(usize::MAX)
+                })
+                .fold(0, usize::saturating_add),
+            (_, Some(_)) => templates.len(),
+            _ => counts.iter().sum(),
+        };
+        let output_error = |err| output_error(err, per_file.is_none());
+        let mut out = match dry_run {
+            true => None,
+            false if is_stdio(&out) => Some(OutputFiles::stdout(format, columns)),
+            false => Some(
+                OutputFiles::new(
+                    &out,
+                    format,
+                    columns,
+                    existing,
+                    new_file,
+                    per_file.map(|n| n as usize),
+                    records,
+                )
+                .map_err(output_error)?,
+            ),
+        };
+        let seed = seed.unwrap_or_else(random);
+        let mut index = 0;
+        // Seeds carry on counting across templates, so that every prompt gets its own
+        let mut first_index = 0;
+        for ((template_index, template), num) in templates.iter().zip(counts) {
+            let mut unique_prompts = None;
+            let prompts: Box<dyn Iterator<Item = Result<Prompt, UniqueError>>> = if exhaustive {
+                let mut seen = HashSet::new();
+                let combinations = template.combinations(Some(max_combinations))?;
+                let normalization = options.normalization.clone();
+                Box::new(
+                    combinations
+                        .map(move |mut prompt| {
+                            if let Some(normalization) = &normalization {
+                                normalization.apply_to(&mut prompt);
+                            }
+                            prompt
+                        })
+                        .filter(move |prompt| !unique || seen.insert(prompt.key()))
+                        .map(Ok),
+                )
+            } else if unique {
+                Box::new(
+                    unique_prompts
+                        .insert(
+                            template
+                                .generate_unique(seed, &options, num, Some(max_combinations))?
+                                .starting_at(first_index),
+                        )
+                        .by_ref(),
+                )
+            } else if let Some(prompt_seed) = prompt_seed {
+                Box::new(std::iter::once(Ok(
+                    template.generate_seeded(prompt_seed, &options)
+                )))
+            } else {
+                let (mut batch, options) = (Batch::new(num), &options);
+                Box::new((0..num as u64).map(move |index| {
+                    let seed = promptifier::prompt_seed(seed, first_index + index);
+                    Ok(template.generate_in_batch(seed, options, &mut batch))
+                }))
+            };
+            for prompt in prompts {
+                let prompt = prompt?;
+                if verbose {
+                    println!("{}", prompt.text);
+                    if let Some(negative) = &prompt.negative {
+                        println!("Negative prompt: {negative}");
+                    }
+                }
+                if let Some(out) = &mut out {
+                    out.write(&Record {
+                        index,
+                        template: split.map(|_| *template_index),
+                        seed: prompt.seed,
+                        prompt: &prompt.text,
+                        negative: prompt.negative.as_deref(),
+                        choices: &prompt.choices,
+                    })
+                    .map_err(output_error)?;
+                }
+                index += 1;
             }
+            // Unique generation uses up a seed on every duplicate it throws away
+            first_index = match &unique_prompts {
+                Some(unique) => unique.next_index(),
+                None => index as u64,
+            };
         }
-        Ok(())
+        if let Some(out) = out {
+            out.finish().map_err(output_error)?;
+        }
+        Ok(ExitCode::SUCCESS)
     })();
-    if let Err(err) = result {
-        eprintln!("{err}");
+    match result {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,105 @@
+//! Clean up of the stray whitespace and separators that empty options leave behind.
+
+use crate::prompt::Prompt;
+
+/// Post-processing applied to generated prompts. Each line of a prompt is cleaned up
+/// separately, so line breaks are always kept.
+#[derive(Clone, Debug)]
+pub struct Normalization {
+    /// Collapse runs of spaces and tabs into a single space, trim the ends of every line, and
+    /// drop blank lines from the start and end of the prompt.
+    pub whitespace: bool,
+    /// Separators to tidy up: runs of separators with nothing but whitespace between them are
+    /// merged into the first, separators at the start or end of a line are removed, and
+    /// whitespace before a separator is dropped. `a , ; cat,` becomes `a, cat`.
+    pub separators: Vec<char>,
+}
+
+impl Normalization {
+    pub const DEFAULT_SEPARATORS: &'static [char] = &[',', ';'];
+
+    /// Apply the normalization to `text`.
+    pub fn apply(&self, text: &str) -> String {
+        let lines: Vec<String> = text.split('\n').map(|line| self.line(line)).collect();
+        let mut lines = &lines[..];
+        if self.whitespace {
+            while let [first, rest @ ..] = lines {
+                if !first.is_empty() {
+                    break;
+                }
+                lines = rest;
+            }
+            while let [rest @ .., last] = lines {
+                if !last.is_empty() {
+                    break;
+                }
+                lines = rest;
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Apply the normalization to both the positive and negative prompt of `prompt`.
+    pub fn apply_to(&self, prompt: &mut Prompt) {
+        prompt.text = self.apply(&prompt.text);
+        if let Some(negative) = &mut prompt.negative {
+            *negative = self.apply(negative);
+        }
+    }
+
+    fn line(&self, line: &str) -> String {
+        let (line, carriage_return) = match line.strip_suffix('\r') {
+            Some(line) => (line, "\r"),
+            None => (line, ""),
+        };
+        let mut line = line.to_string();
+        if !self.separators.is_empty() {
+            line = tidy_separators(&line, &self.separators);
+        }
+        if self.whitespace {
+            line = line
+                .split([' ', '\t'])
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
+        line + carriage_return
+    }
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self {
+            whitespace: true,
+            separators: Self::DEFAULT_SEPARATORS.to_vec(),
+        }
+    }
+}
+
+/// Tidy every one of `separators` in `line`. Where several separators end up next to each
+/// other, the first is kept.
+fn tidy_separators(line: &str, separators: &[char]) -> String {
+    let mut pieces = Vec::new();
+    let mut rest = line;
+    while let Some(index) = rest.find(separators) {
+        let separator = rest[index..].chars().next().unwrap();
+        pieces.push((&rest[..index], Some(separator)));
+        rest = &rest[index + separator.len_utf8()..];
+    }
+    pieces.push((rest, None));
+    let mut tidied = String::with_capacity(line.len());
+    let mut pending = None;
+    for (piece, separator) in pieces {
+        if !piece.trim().is_empty() {
+            if let Some(separator) = pending.take() {
+                tidied.truncate(tidied.trim_end().len());
+                tidied.push(separator);
+            }
+            tidied.push_str(piece);
+        }
+        if !tidied.is_empty() {
+            pending = pending.or(separator);
+        }
+    }
+    tidied
+}
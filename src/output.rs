@@ -0,0 +1,366 @@
+//! Serialization of generated prompts to the supported output formats.
+
+use crate::prompt::Pick;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File format to write generated prompts in.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, Serialize)]
+pub enum Format {
+    /// One prompt per line, each followed by a `Negative prompt:` line if it has one
+    #[default]
+    Text,
+    /// One JSON object per line, including the choices made
+    Jsonl,
+    /// A single JSON array of objects, including the choices made
+    Json,
+    /// Comma separated values, with the choices made as a JSON encoded column
+    Csv,
+}
+
+/// A generated prompt as written to structured output formats.
+#[derive(Clone, Debug, Serialize)]
+pub struct Record<'a> {
+    /// Position of the prompt within the batch.
+    pub index: usize,
+    /// Position of the template the prompt was generated from, when generating from several.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<usize>,
+    /// Seed the prompt was generated with, if it was randomly generated.
+    pub seed: Option<u64>,
+    pub prompt: &'a str,
+    /// Negative prompt generated alongside, for templates with a negative section.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative: Option<&'a str>,
+    pub choices: &'a [Pick],
+}
+
+/// Quote `field` for inclusion in a CSV row, if needed.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Optional columns of CSV output, decided before anything is written so that every row has
+/// the same ones.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Columns {
+    /// Position of the template each prompt was generated from, when generating from several.
+    pub templates: bool,
+    /// Negative prompts, when any template has a negative section.
+    pub negatives: bool,
+}
+
+/// Writes [`Record`]s to `W` in a given [`Format`].
+pub struct Writer<W: Write> {
+    inner: W,
+    format: Format,
+    columns: Columns,
+    written: usize,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W, format: Format, columns: Columns) -> Self {
+        Self {
+            inner,
+            format,
+            columns,
+            written: 0,
+        }
+    }
+
+    fn csv_header(&self) -> String {
+        let mut columns = vec!["index"];
+        if self.columns.templates {
+            columns.push("template");
+        }
+        columns.extend(["seed", "prompt"]);
+        if self.columns.negatives {
+            columns.push("negative");
+        }
+        columns.push("choices");
+        columns.join(",")
+    }
+
+    /// Write records following on from earlier output in the same format, such as when
+    /// appending to a file, so no CSV header is written.
+    pub fn continuing(inner: W, format: Format, columns: Columns) -> Self {
+        Self {
+            written: 1,
+            ..Self::new(inner, format, columns)
+        }
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let first = self.written == 0;
+        self.written += 1;
+        match self.format {
+            Format::Text => {
+                writeln!(self.inner, "{}", record.prompt)?;
+                match record.negative {
+                    Some(negative) => writeln!(self.inner, "Negative prompt: {negative}"),
+                    None => Ok(()),
+                }
+            }
+            Format::Jsonl => {
+                serde_json::to_writer(&mut self.inner, record)?;
+                writeln!(self.inner)
+            }
+            Format::Json => {
+                write!(self.inner, "{}", if first { "[\n  " } else { ",\n  " })?;
+                serde_json::to_writer(&mut self.inner, record).map_err(io::Error::from)
+            }
+            Format::Csv => {
+                if first {
+                    writeln!(self.inner, "{}", self.csv_header())?;
+                }
+                write!(self.inner, "{},", record.index)?;
+                if self.columns.templates {
+                    let template = record.template.map_or(String::new(), |t| t.to_string());
+                    write!(self.inner, "{template},")?;
+                }
+                write!(
+                    self.inner,
+                    "{},{},",
+                    record.seed.map_or(String::new(), |seed| seed.to_string()),
+                    csv_field(record.prompt),
+                )?;
+                if self.columns.negatives {
+                    write!(self.inner, "{},", csv_field(record.negative.unwrap_or("")))?;
+                }
+                writeln!(
+                    self.inner,
+                    "{}",
+                    csv_field(&serde_json::to_string(record.choices)?)
+                )
+            }
+        }
+    }
+
+    /// Write anything needed to close off the output, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.format {
+            Format::Json if self.written == 0 => writeln!(self.inner, "[]")?,
+            Format::Json => writeln!(self.inner, "\n]")?,
+            Format::Csv if self.written == 0 => writeln!(self.inner, "{}", self.csv_header())?,
+            _ => {}
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// How to treat an output file that already exists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Existing {
+    /// Fail rather than touch it
+    Refuse,
+    Overwrite,
+    /// Add new records after those already in it
+    Append,
+}
+
+/// Scheme for naming a new output file on every run, rather than reusing the same one.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum FileNaming {
+    /// `prompts-1.txt`, `prompts-2.txt` and so on, using the lowest number not already taken
+    Numbered,
+    /// `prompts-20240131-142500.txt`, from the UTC time of the run
+    Timestamped,
+}
+
+/// `path` with `suffix` added to the end of its file stem, before the extension.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}{suffix}.{}", extension.to_string_lossy()),
+        None => format!("{stem}{suffix}"),
+    };
+    path.with_file_name(name)
+}
+
+/// The current UTC time as `YYYYMMDD-HHMMSS`.
+fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    // Convert days since the epoch to a civil date, following Howard Hinnant's
+    // `civil_from_days`
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Path of the `part`th file written for `base`, when starting a new file every `per_file`
+/// records if set.
+fn part_path(base: &Path, per_file: Option<usize>, part: usize) -> PathBuf {
+    match per_file {
+        Some(_) => with_suffix(base, &format!("-part{part}")),
+        None => base.to_path_buf(),
+    }
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("Output file '{}' already exists", path.display()),
+    )
+}
+
+/// Writes [`Record`]s to standard output or to a file, optionally starting a new file every
+/// so many records: `prompts-part1.txt`, `prompts-part2.txt` and so on.
+pub struct OutputFiles {
+    /// Path to name files after, or `None` for standard output.
+    path: Option<PathBuf>,
+    format: Format,
+    columns: Columns,
+    existing: Existing,
+    per_file: Option<usize>,
+    writer: Option<Writer<BufWriter<Box<dyn Write>>>>,
+    /// Number of records written to the current file.
+    in_file: usize,
+    /// Number of files opened so far.
+    files: usize,
+}
+
+impl OutputFiles {
+    /// Write to standard output.
+    pub fn stdout(format: Format, columns: Columns) -> Self {
+        let writer: Box<dyn Write> = Box::new(io::stdout());
+        Self {
+            path: None,
+            format,
+            columns,
+            existing: Existing::Overwrite,
+            per_file: None,
+            writer: Some(Writer::new(BufWriter::new(writer), format, columns)),
+            in_file: 0,
+            files: 1,
+        }
+    }
+
+    /// Write `records` records to `path`, or a file named after it with `naming`, starting a
+    /// new file every `per_file` records if set. Any of those files that already exist are
+    /// refused straight away, but files are only created once there is something to write to
+    /// them, so that a run which fails before then leaves nothing behind.
+    pub fn new(
+        path: &Path,
+        format: Format,
+        columns: Columns,
+        existing: Existing,
+        naming: Option<FileNaming>,
+        per_file: Option<usize>,
+        records: usize,
+    ) -> io::Result<Self> {
+        if existing == Existing::Append && matches!(format, Format::Json) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't append to a JSON array; use the jsonl format instead",
+            ));
+        }
+        let parts = per_file.map_or(1, |per_file| records.div_ceil(per_file).max(1));
+        let taken = |path: &Path| {
+            (1..=parts)
+                .map(|part| part_path(path, per_file, part))
+                .find(|path| path.exists())
+        };
+        let path = match naming {
+            None => path.to_path_buf(),
+            Some(FileNaming::Timestamped) => with_suffix(path, &format!("-{}", timestamp())),
+            Some(FileNaming::Numbered) => (1..)
+                .map(|number| with_suffix(path, &format!("-{number}")))
+                .find(|path| taken(path).is_none())
+                .unwrap(),
+        };
+        if let (Existing::Refuse, Some(taken)) = (existing, taken(&path)) {
+            return Err(already_exists(&taken));
+        }
+        Ok(Self {
+            path: Some(path),
+            format,
+            columns,
+            existing,
+            per_file,
+            writer: None,
+            in_file: 0,
+            files: 0,
+        })
+    }
+
+    /// Finish off the current file, if any, and open the next one.
+    fn open_next(&mut self) -> io::Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.finish()?;
+        }
+        self.files += 1;
+        let path = part_path(self.path.as_ref().unwrap(), self.per_file, self.files);
+        let mut options = OpenOptions::new();
+        match self.existing {
+            Existing::Refuse => options.write(true).create_new(true),
+            Existing::Overwrite => options.write(true).create(true).truncate(true),
+            Existing::Append => options.append(true).create(true),
+        };
+        let file = options.open(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::AlreadyExists => already_exists(&path),
+            _ => err,
+        })?;
+        let continuing = file.metadata()?.len() > 0;
+        let file: Box<dyn Write> = Box::new(file);
+        let file = BufWriter::new(file);
+        self.writer = Some(if continuing {
+            Writer::continuing(file, self.format, self.columns)
+        } else {
+            Writer::new(file, self.format, self.columns)
+        });
+        self.in_file = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.writer.is_none()
+            || self
+                .per_file
+                .is_some_and(|per_file| self.in_file == per_file)
+        {
+            self.open_next()?;
+        }
+        self.in_file += 1;
+        self.writer.as_mut().unwrap().write(record)
+    }
+
+    /// Finish off the file currently being written, creating it first if nothing was.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.files == 0 {
+            self.open_next()?;
+        }
+        match self.writer.take() {
+            Some(writer) => writer.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
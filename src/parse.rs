@@ -0,0 +1,1000 @@
+//! Conversion of template source text into a [`Group`] tree.
+
+use crate::ast::{Choice, Group, Node, Selection, Span, Variable};
+use crate::diagnostic::{ParseErrors, Source};
+use crate::sample::ChoiceGuidance;
+use crate::Template;
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::num::ParseFloatError;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Line separating a template's positive prompt from its negative prompt.
+pub const NEGATIVE_SEPARATOR: &str = "---negative---";
+
+/// Find the [`NEGATIVE_SEPARATOR`] line within `range` of `source`, if there is one, returning
+/// the ranges of the positive and negative prompts either side of it.
+fn split_negative(source: &str, range: Span) -> Option<(Span, Span)> {
+    let mut start = range.start;
+    for line in source[range.clone()].split_inclusive('\n') {
+        if line.trim() == NEGATIVE_SEPARATOR {
+            let positive = &source[range.start..start];
+            let positive = positive.strip_suffix('\n').unwrap_or(positive);
+            let positive = positive.strip_suffix('\r').unwrap_or(positive);
+            let negative_start = (start + line.len()).min(range.end);
+            return Some((
+                range.start..range.start + positive.len(),
+                negative_start..range.end,
+            ));
+        }
+        start += line.len();
+    }
+    None
+}
+
+/// A problem found in a template, located by a byte span in one of the sources it was parsed
+/// from. See [`ParseErrors`] for rendering errors along with the text they point at.
+#[derive(Clone, Debug, Error)]
+#[error("{kind}")]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// Index of the source the error occurred in, within [`ParseErrors::sources`]. The
+    /// template itself is always source 0.
+    pub source_index: usize,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, Error)]
+pub enum ParseErrorKind {
+    #[error("Unexpected closing brace")]
+    UnexpectedClosingBrace,
+    #[error("Unclosed open brace")]
+    UnclosedBrace,
+    #[error("Unclosed block comment")]
+    UnclosedComment,
+    #[error("Invalid weight specifier {0}")]
+    InvalidWeightSpecifier(ParseWeightError),
+    #[error("Variable '{0}' is never defined")]
+    UndefinedVariable(String),
+    #[error("Variable '{0}' is already defined")]
+    DuplicateVariable(String),
+    #[error("Variable '{0}' refers to itself")]
+    RecursiveVariable(String),
+    #[error("Could not read wildcard '{0}': {1}")]
+    UnreadableWildcard(String, Arc<std::io::Error>),
+    #[error("Wildcard '{0}' has no options")]
+    EmptyWildcard(String),
+    #[error("Wildcard '{0}' refers to itself")]
+    RecursiveWildcard(String),
+    #[error("Could not include '{0}': {1}")]
+    UnreadableInclude(String, Arc<std::io::Error>),
+    #[error("Included file '{0}' includes itself")]
+    RecursiveInclude(String),
+    #[error("Unknown choice guidance '{0}'")]
+    UnknownGuidance(String),
+    #[error("Invalid temperature '{0}'")]
+    InvalidTemperature(String),
+    #[error("Invalid selection range '{0}'")]
+    InvalidSelection(String),
+}
+
+impl ParseErrorKind {
+    /// Short identifier for the kind of error, for tools consuming diagnostics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseErrorKind::UnexpectedClosingBrace => "unexpected_closing_brace",
+            ParseErrorKind::UnclosedBrace => "unclosed_brace",
+            ParseErrorKind::UnclosedComment => "unclosed_comment",
+            ParseErrorKind::InvalidWeightSpecifier(_) => "invalid_weight_specifier",
+            ParseErrorKind::UndefinedVariable(_) => "undefined_variable",
+            ParseErrorKind::DuplicateVariable(_) => "duplicate_variable",
+            ParseErrorKind::RecursiveVariable(_) => "recursive_variable",
+            ParseErrorKind::UnreadableWildcard(..) => "unreadable_wildcard",
+            ParseErrorKind::EmptyWildcard(_) => "empty_wildcard",
+            ParseErrorKind::RecursiveWildcard(_) => "recursive_wildcard",
+            ParseErrorKind::UnreadableInclude(..) => "unreadable_include",
+            ParseErrorKind::RecursiveInclude(_) => "recursive_include",
+            ParseErrorKind::UnknownGuidance(_) => "unknown_guidance",
+            ParseErrorKind::InvalidTemperature(_) => "invalid_temperature",
+            ParseErrorKind::InvalidSelection(_) => "invalid_selection",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Error)]
+#[error("'{specifier}': {parse_error}")]
+pub struct ParseWeightError {
+    pub specifier: String,
+    pub index: usize,
+    pub parse_error: ParseWeightErrorKind,
+}
+
+#[derive(Clone, Debug, Error)]
+pub enum ParseWeightErrorKind {
+    #[error("{0}")]
+    FloatParse(#[from] ParseFloatError),
+    #[error("Weights cannot be negative")]
+    NegativeWeight,
+}
+
+/// Characters that lose their special meaning when preceded by a backslash.
+const ESCAPABLE: &[char] = &['{', '}', '|', ':', '$', '_', '#', '/', '!', '~', '\\'];
+
+/// Iterate over the characters of `raw` along with their byte index, and whether they were
+/// escaped. Escaping backslashes are consumed; any other backslash is passed through as is.
+fn unescaped_chars(raw: &str) -> impl Iterator<Item = (usize, char, bool)> + '_ {
+    let mut chars = raw.char_indices().peekable();
+    std::iter::from_fn(move || {
+        let (index, c) = chars.next()?;
+        match chars.peek() {
+            Some(&(_, next)) if c == '\\' && ESCAPABLE.contains(&next) => {
+                chars.next();
+                Some((index, next, true))
+            }
+            _ => Some((index, c, false)),
+        }
+    })
+}
+
+/// Resolve the escape sequences in `raw`.
+pub fn unescape(raw: &str) -> Cow<'_, str> {
+    if raw.contains('\\') {
+        Cow::Owned(unescaped_chars(raw).map(|(_, c, _)| c).collect())
+    } else {
+        Cow::Borrowed(raw)
+    }
+}
+
+/// Escape every character in `text` that would otherwise be interpreted as template syntax.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if ESCAPABLE.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Split a trailing `:weight` specifier off of `maybe_weighted`, returning the remaining text
+/// with its escape sequences resolved, and the parsed weight. Text without a specifier has a
+/// weight of 1. Escaped colons are never treated as the start of a specifier, and whitespace
+/// around the weight is ignored.
+pub fn parse_weight(
+    maybe_weighted: &str,
+    ignore_invalid_weight_literals: bool,
+) -> Result<(Cow<'_, str>, f64), ParseWeightError> {
+    let Some(colon) = unescaped_chars(maybe_weighted)
+        .filter(|&(_, c, escaped)| c == ':' && !escaped)
+        .last()
+        .map(|(index, ..)| index)
+    else {
+        return Ok((unescape(maybe_weighted), 1.0));
+    };
+    let (text, weight_text) = (&maybe_weighted[..colon], &maybe_weighted[colon + 1..]);
+    let maybe_weight = weight_text
+        .trim()
+        .parse()
+        .map_err(|parse_error| ParseWeightError {
+            specifier: weight_text.to_string(),
+            index: text.len() + 1,
+            parse_error: ParseWeightErrorKind::FloatParse(parse_error),
+        });
+    match maybe_weight {
+        Ok(weight) if weight >= 0.0 => Ok((unescape(text), weight)),
+        Ok(_) => Err(ParseWeightError {
+            specifier: weight_text.to_string(),
+            index: text.len() + 1,
+            parse_error: ParseWeightErrorKind::NegativeWeight,
+        }),
+        _ if ignore_invalid_weight_literals => Ok((unescape(maybe_weighted), 1.0)),
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    pub ignore_invalid_weight_literals: bool,
+    /// Directory to resolve `__name__` wildcards against, as `<dir>/name.txt`. Wildcards are
+    /// left as literal text when this is not set.
+    pub wildcard_dir: Option<PathBuf>,
+    /// Directory to resolve `{@path}` includes in the template against, usually the one the
+    /// template was read from. Defaults to the working directory; paths in included files are
+    /// always resolved against the file's own directory.
+    pub include_dir: Option<PathBuf>,
+    /// Name to refer to the template by in diagnostics, such as the file it was read from.
+    /// Defaults to `<template>`.
+    pub source_name: Option<String>,
+    /// File the template was read from, if any, so that an include leading back to it is
+    /// reported as a cycle.
+    pub source_path: Option<PathBuf>,
+}
+
+/// A group that is still being parsed.
+struct Frame {
+    start_index: usize,
+    binding: Option<String>,
+    selection: Option<Selection>,
+    guidance: Option<ChoiceGuidance>,
+    temperature: Option<f64>,
+    options: Vec<Choice>,
+    top: Choice,
+}
+
+impl Frame {
+    fn new(start_index: usize, content_start: usize) -> Self {
+        Self {
+            start_index,
+            binding: None,
+            selection: None,
+            guidance: None,
+            temperature: None,
+            options: Vec::new(),
+            top: Choice::new(content_start),
+        }
+    }
+
+    fn push(&mut self, end: usize, next_start: usize) {
+        self.top.span.end = end;
+        self.options
+            .push(std::mem::replace(&mut self.top, Choice::new(next_start)));
+    }
+
+    /// Close the group at `end`, just past its closing brace if it has one, which the last
+    /// option ends before.
+    fn finish(mut self, end: usize, closed: bool) -> Group {
+        self.top.span.end = end - closed as usize;
+        self.options.push(self.top);
+        Group {
+            // Numbered once the whole template is parsed
+            id: 0,
+            span: self.start_index..end,
+            options: self.options,
+            wildcard: None,
+            include: None,
+            source: None,
+            selection: self.selection,
+            guidance: self.guidance,
+            temperature: self.temperature,
+            locked: None,
+        }
+    }
+}
+
+/// Map the byte index `index` within text joined up from `pieces`, as held by [`Stack`], back
+/// to the source.
+fn source_index(pieces: &[(usize, usize)], index: usize) -> usize {
+    let (joined, source) = pieces
+        .iter()
+        .rev()
+        .find(|&&(joined, _)| joined <= index)
+        .unwrap_or(&pieces[0]);
+    source + index - joined
+}
+
+struct Stack {
+    stack: Vec<Frame>,
+    top: Frame,
+    /// Raw text of the current segment that came before a comment. It's joined up with the
+    /// text following the comment once the segment ends.
+    pending: String,
+    /// Where each piece of `pending` came from, as its byte index within `pending` and within
+    /// the source.
+    pieces: Vec<(usize, usize)>,
+}
+
+impl Stack {
+    fn new(start: usize) -> Self {
+        Self {
+            stack: Vec::new(),
+            top: Frame::new(start, start),
+            pending: String::new(),
+            pieces: Vec::new(),
+        }
+    }
+
+    /// Hold on to the raw text `raw`, starting at `start`, until the segment it's part of ends.
+    fn hold(&mut self, raw: &str, start: usize) {
+        self.pieces.push((self.pending.len(), start));
+        self.pending.push_str(raw);
+    }
+
+    /// The raw text of a segment ending in `raw`, including any pending text before it.
+    fn segment<'a>(&mut self, raw: &'a str) -> Cow<'a, str> {
+        self.pieces.clear();
+        if self.pending.is_empty() {
+            Cow::Borrowed(raw)
+        } else {
+            let mut segment = std::mem::take(&mut self.pending);
+            segment.push_str(raw);
+            Cow::Owned(segment)
+        }
+    }
+
+    /// Append the raw text `raw` to the option being parsed, after any pending text.
+    fn push_raw(&mut self, raw: &str) {
+        let segment = self.segment(raw);
+        push_text(&mut self.top.top.nodes, &unescape(&segment));
+    }
+
+    fn push(&mut self, start_index: usize, content_start: usize) {
+        self.stack.push(std::mem::replace(
+            &mut self.top,
+            Frame::new(start_index, content_start),
+        ));
+    }
+
+    fn pop(&mut self) -> Option<Frame> {
+        self.stack
+            .pop()
+            .map(|frame| std::mem::replace(&mut self.top, frame))
+    }
+}
+
+/// Where in which source something appears, as a source index and byte span.
+type Location = (usize, Span);
+
+/// Variables encountered so far, indexed in order of first appearance.
+#[derive(Default)]
+struct Variables {
+    names: Vec<String>,
+    groups: Vec<Option<(Group, Location)>>,
+    first_use: Vec<Location>,
+}
+
+impl Variables {
+    fn index(&mut self, name: &str, used_at: Location) -> usize {
+        match self.names.iter().position(|n| n == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name.to_string());
+                self.groups.push(None);
+                self.first_use.push(used_at);
+                self.names.len() - 1
+            }
+        }
+    }
+
+    /// Define `name` as `group`. Redefinitions are reported, and the first definition kept.
+    fn define(
+        &mut self,
+        name: String,
+        group: Group,
+        source_index: usize,
+        errors: &mut Vec<ParseError>,
+    ) -> usize {
+        let defined_at = (source_index, group.span.clone());
+        let index = self.index(&name, defined_at.clone());
+        if self.groups[index].is_some() {
+            errors.push(ParseError {
+                kind: ParseErrorKind::DuplicateVariable(name),
+                source_index,
+                span: group.span,
+            });
+        } else {
+            self.groups[index] = Some((group, defined_at));
+        }
+        index
+    }
+
+    /// Check that every variable is defined and none are defined in terms of themselves,
+    /// reporting each one that isn't.
+    fn finish(self, errors: &mut Vec<ParseError>) -> Vec<Variable> {
+        let Variables {
+            names,
+            groups,
+            first_use,
+        } = self;
+        let total = names.len();
+        let mut locations = Vec::new();
+        let mut variables = Vec::new();
+        for ((name, group), (source_index, span)) in names.into_iter().zip(groups).zip(first_use) {
+            match group {
+                Some((group, defined_at)) => {
+                    variables.push(Variable {
+                        name,
+                        group,
+                        source_index: defined_at.0,
+                    });
+                    locations.push(defined_at);
+                }
+                None => errors.push(ParseError {
+                    kind: ParseErrorKind::UndefinedVariable(name),
+                    source_index,
+                    span,
+                }),
+            }
+        }
+        // References index into the full list, so cycles can only be checked once it's complete
+        if variables.len() < total {
+            return variables;
+        }
+        fn visit(
+            variables: &[Variable],
+            index: usize,
+            visiting: &mut Vec<usize>,
+            done: &mut [bool],
+        ) -> Option<usize> {
+            if done[index] {
+                return None;
+            }
+            if visiting.contains(&index) {
+                return Some(index);
+            }
+            visiting.push(index);
+            for reference in variables[index].group.references() {
+                if let Some(recursive) = visit(variables, reference, visiting, done) {
+                    return Some(recursive);
+                }
+            }
+            visiting.pop();
+            done[index] = true;
+            None
+        }
+        let mut done = vec![false; variables.len()];
+        for index in 0..variables.len() {
+            let mut visiting = Vec::new();
+            if let Some(recursive) = visit(&variables, index, &mut visiting, &mut done) {
+                // Skip over the rest of the cycle so it's only reported once
+                for &index in &visiting {
+                    done[index] = true;
+                }
+                let (source_index, span) = locations[recursive].clone();
+                errors.push(ParseError {
+                    kind: ParseErrorKind::RecursiveVariable(variables[recursive].name.clone()),
+                    source_index,
+                    span,
+                });
+            }
+        }
+        variables
+    }
+}
+
+/// Read the variable name at the start of `text`, if there is one.
+fn identifier(text: &str) -> Option<&str> {
+    let end = text
+        .char_indices()
+        .find(|&(_, c)| !(c.is_alphanumeric() || c == '_'))
+        .map_or(text.len(), |(index, _)| index);
+    let name = &text[..end];
+    name.chars()
+        .next()
+        .filter(|c| !c.is_numeric())
+        .map(|_| name)
+}
+
+/// Read a `$name=` binding from the start of a group's contents, if there is one.
+fn binding(text: &str) -> Option<&str> {
+    let name = identifier(text.strip_prefix('$')?)?;
+    text[1 + name.len()..].starts_with('=').then_some(name)
+}
+
+/// Read a header made up of `prefix`, a word of characters matching `word`, and a colon from
+/// the start of a group's contents, returning the word along with the length of text the
+/// header takes up, including any spaces after the colon.
+fn header(text: &str, prefix: char, word: impl Fn(char) -> bool) -> Option<(&str, usize)> {
+    let rest = text.strip_prefix(prefix)?;
+    let end = rest.find(|c: char| !word(c))?;
+    let after = rest[end..].strip_prefix(':')?;
+    let spaces = after.len() - after.trim_start_matches([' ', '\t']).len();
+    (end > 0).then_some((&rest[..end], end + 2 + spaces))
+}
+
+/// Read a `!name:` guidance override from the start of a group's contents.
+fn guidance(text: &str) -> Option<(&str, usize)> {
+    header(text, '!', |c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Read a `~0.5:` temperature override from the start of a group's contents. Any word is
+/// read, so that a mistyped temperature is reported rather than taken as an option.
+fn temperature(text: &str) -> Option<(&str, usize)> {
+    header(text, '~', |c| {
+        c.is_alphanumeric() || matches!(c, '.' | '-' | '+' | '_')
+    })
+}
+
+/// Read a `N$$`, `N-M$$` or `N-M$$separator$$` selection from the start of a group's
+/// contents, returning it along with the length of text it takes up. A count that isn't a
+/// number or a range from a smaller number to a larger one is returned as the error.
+fn selection(text: &str) -> Option<(Result<Selection, &str>, usize)> {
+    let count_length = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '-'))
+        .unwrap_or(text.len());
+    let (count, rest) = (
+        &text[..count_length],
+        text[count_length..].strip_prefix("$$")?,
+    );
+    if count.is_empty() {
+        return None;
+    }
+    let range = match count.split_once('-') {
+        Some((min, max)) => min.parse().ok().zip(max.parse().ok()),
+        None => count.parse().ok().map(|count| (count, count)),
+    };
+    let mut length = count.len() + 2;
+    let first_option = &rest[..rest.find(['|', '{', '}']).unwrap_or(rest.len())];
+    let separator = match first_option.split_once("$$") {
+        Some((separator, _)) => {
+            length += separator.len() + 2;
+            unescape(separator).into_owned()
+        }
+        None => Selection::DEFAULT_SEPARATOR.to_string(),
+    };
+    let selection = match range {
+        Some((min, max)) if min <= max => Ok(Selection {
+            min,
+            max,
+            separator,
+        }),
+        _ => Err(count),
+    };
+    Some((selection, length))
+}
+
+/// Read an `@path}` include directive from the start of a group's contents, if there is one,
+/// returning the path along with the length of text the directive takes up.
+fn include_path(text: &str) -> Option<(&str, usize)> {
+    let rest = text.strip_prefix('@')?;
+    let end = rest.find(['{', '}', '|', '\n'])?;
+    let path = rest[..end].trim();
+    (rest[end..].starts_with('}') && !path.is_empty()).then_some((path, end + 2))
+}
+
+fn push_text(nodes: &mut Vec<Node>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match nodes.last_mut() {
+        Some(Node::Text(last)) => last.push_str(text),
+        _ => nodes.push(Node::Text(text.to_string())),
+    }
+}
+
+/// Read a `__name__` wildcard from the start of `text`, which should directly follow the
+/// opening underscores, if there is one. Names can't lead outside of the wildcard directory,
+/// so they can't start with `/` or have `..` components.
+fn wildcard_name(text: &str) -> Option<&str> {
+    let (name, _) = text.split_once("__")?;
+    (!name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || ['_', '-', '.', '/'].contains(&c))
+        && !name.starts_with('/')
+        && !name.split('/').any(|component| component == ".."))
+    .then_some(name)
+}
+
+struct Parser<'a> {
+    options: &'a ParseOptions,
+    variables: Variables,
+    wildcards: HashMap<String, Group>,
+    resolving: Vec<String>,
+    sources: Vec<Source>,
+    /// Directory that includes in each source are resolved against.
+    dirs: Vec<PathBuf>,
+    /// Index of the source currently being parsed.
+    source_index: usize,
+    /// Files currently being included, innermost last.
+    including: Vec<PathBuf>,
+    errors: Vec<ParseError>,
+}
+
+impl Parser<'_> {
+    fn error(&mut self, kind: ParseErrorKind, span: Span) {
+        self.errors.push(ParseError {
+            kind,
+            source_index: self.source_index,
+            span,
+        });
+    }
+
+    /// Apply the trailing `:weight` specifier in `text` to the option being parsed. An invalid
+    /// specifier is reported, and the option is given the full text with a weight of 1.
+    fn apply_weight(&mut self, text: &str, stack: &mut Stack, segment_start: usize) {
+        let mut pieces = stack.pieces.clone();
+        pieces.push((stack.pending.len(), segment_start));
+        let text = stack.segment(text);
+        let (text, weight) = match parse_weight(&text, self.options.ignore_invalid_weight_literals)
+        {
+            Ok(weighted) => weighted,
+            Err(err) => {
+                // The specifier may run across comments left out of the text, so map each
+                // end back to the source separately
+                let start = source_index(&pieces, err.index);
+                let span = match err.specifier.len() {
+                    0 => start..start,
+                    length => start..source_index(&pieces, err.index + length - 1) + 1,
+                };
+                self.error(ParseErrorKind::InvalidWeightSpecifier(err), span);
+                (unescape(&text), 1.0)
+            }
+        };
+        push_text(&mut stack.top.top.nodes, &text);
+        stack.top.top.weight = weight;
+    }
+
+    /// Parse `source`, from the byte index `start` on, into the group of top level options it
+    /// describes. If `lines` is set, each line is a separate option, as in a wildcard file,
+    /// and blank lines are skipped. Errors are recorded and parsing carries on past them, so
+    /// that as many as possible are reported at once.
+    fn group(&mut self, source: &str, start: usize, lines: bool) -> Group {
+        let mut stack = Stack::new(start);
+        let mut segment_start = start;
+        let chars = unescaped_chars(&source[start..]);
+        for (index, c, escaped) in chars.map(|(index, c, escaped)| (start + index, c, escaped)) {
+            if escaped
+                || index < segment_start
+                || !['|', '{', '}', '$', '_', '#', '/', '\n'].contains(&c)
+            {
+                continue;
+            }
+            let pre = &source[segment_start..index];
+            let post = &source[index + 1..];
+            match c {
+                '|' => {
+                    self.apply_weight(pre, &mut stack, segment_start);
+                    stack.top.push(index, index + 1);
+                    segment_start = index + 1;
+                }
+                '\n' if lines && stack.stack.is_empty() => {
+                    let line = pre.strip_suffix('\r').unwrap_or(pre);
+                    if stack.top.top.nodes.is_empty()
+                        && stack.pending.is_empty()
+                        && line.trim().is_empty()
+                    {
+                        stack.top.top = Choice::new(index + 1);
+                    } else {
+                        self.apply_weight(line, &mut stack, segment_start);
+                        stack.top.push(segment_start + line.len(), index + 1);
+                    }
+                    segment_start = index + 1;
+                }
+                '{' if include_path(post).is_some() => {
+                    stack.push_raw(pre);
+                    let (path, length) = include_path(post).unwrap();
+                    if let Some(group) = self.include(path, index..index + 1 + length) {
+                        stack.top.top.nodes.push(Node::Group(group));
+                    }
+                    segment_start = index + 1 + length;
+                }
+                '{' => {
+                    stack.push_raw(pre);
+                    let mut header = post;
+                    let binding = binding(header);
+                    if let Some(name) = binding {
+                        header = &header[name.len() + 2..];
+                    }
+                    let mut guidance_override = None;
+                    if let Some((name, length)) = guidance(header) {
+                        let name_start = index + 2 + post.len() - header.len();
+                        match ChoiceGuidance::from_str(name, true) {
+                            Ok(guidance) => guidance_override = Some(guidance),
+                            Err(_) => self.error(
+                                ParseErrorKind::UnknownGuidance(name.to_string()),
+                                name_start..name_start + name.len(),
+                            ),
+                        }
+                        header = &header[length..];
+                    }
+                    let mut temperature_override = None;
+                    if let Some((number, length)) = temperature(header) {
+                        let number_start = index + 2 + post.len() - header.len();
+                        match number.parse::<f64>() {
+                            Ok(temperature) if temperature >= 0.0 => {
+                                temperature_override = Some(temperature)
+                            }
+                            _ => self.error(
+                                ParseErrorKind::InvalidTemperature(number.to_string()),
+                                number_start..number_start + number.len(),
+                            ),
+                        }
+                        header = &header[length..];
+                    }
+                    let mut selection_override = None;
+                    if let Some((selection, length)) = selection(header) {
+                        let count_start = index + 1 + post.len() - header.len();
+                        match selection {
+                            Ok(selection) => selection_override = Some(selection),
+                            Err(count) => self.error(
+                                ParseErrorKind::InvalidSelection(count.to_string()),
+                                count_start..count_start + count.len(),
+                            ),
+                        }
+                        header = &header[length..];
+                    }
+                    segment_start = index + 1 + post.len() - header.len();
+                    stack.push(index, segment_start);
+                    stack.top.binding = binding.map(str::to_string);
+                    stack.top.guidance = guidance_override;
+                    stack.top.temperature = temperature_override;
+                    stack.top.selection = selection_override;
+                }
+                '}' if stack.stack.is_empty() => {
+                    // Leave the stray brace in the text and carry on
+                    self.error(ParseErrorKind::UnexpectedClosingBrace, index..index + 1);
+                }
+                '}' => {
+                    self.apply_weight(pre, &mut stack, segment_start);
+                    let mut frame = stack.pop().unwrap();
+                    let binding = frame.binding.take();
+                    let group = frame.finish(index + 1, true);
+                    let node = match binding {
+                        Some(name) => Node::Variable(self.variables.define(
+                            name,
+                            group,
+                            self.source_index,
+                            &mut self.errors,
+                        )),
+                        None => Node::Group(group),
+                    };
+                    stack.top.top.nodes.push(node);
+                    segment_start = index + 1;
+                }
+                '$' => {
+                    let Some(name) = identifier(post) else {
+                        continue;
+                    };
+                    stack.push_raw(pre);
+                    let used_at = (self.source_index, index..index + 1 + name.len());
+                    let variable = self.variables.index(name, used_at);
+                    stack.top.top.nodes.push(Node::Variable(variable));
+                    segment_start = index + 1 + name.len();
+                }
+                '_' => {
+                    let Some(name) = post
+                        .strip_prefix('_')
+                        .filter(|_| self.options.wildcard_dir.is_some())
+                        .and_then(wildcard_name)
+                    else {
+                        continue;
+                    };
+                    stack.push_raw(pre);
+                    if let Some(group) = self.wildcard(name, index) {
+                        stack.top.top.nodes.push(Node::Group(group));
+                    }
+                    segment_start = index + name.len() + 4;
+                }
+                // Only at the start of a line or after whitespace, so that `C#` and `#ff0000`
+                // are left alone
+                '#' if !source[..index]
+                    .chars()
+                    .next_back()
+                    .is_none_or(char::is_whitespace) => {}
+                '#' => {
+                    stack.hold(pre.trim_end_matches([' ', '\t']), segment_start);
+                    let line_start = source[..index].rfind('\n').map_or(0, |start| start + 1);
+                    // A comment on a line of its own takes the whole line with it; otherwise
+                    // the newline ending it is kept, as it may end an option
+                    let own_line = source[line_start..index].trim().is_empty() as usize;
+                    segment_start = post
+                        .find('\n')
+                        .map_or(source.len(), |end| index + 1 + end + own_line);
+                }
+                '/' if post.starts_with('*') => {
+                    stack.hold(pre, segment_start);
+                    segment_start = match post[1..].find("*/") {
+                        Some(end) => index + end + 4,
+                        None => {
+                            self.error(ParseErrorKind::UnclosedComment, index..index + 2);
+                            source.len()
+                        }
+                    };
+                }
+                _ => {}
+            }
+        }
+        let mut rest = &source[segment_start..];
+        if !stack.stack.is_empty() {
+            stack.push_raw(rest);
+            rest = "";
+            while let Some(frame) = stack.pop() {
+                let start = frame.start_index;
+                self.error(ParseErrorKind::UnclosedBrace, start..start + 1);
+                let group = frame.finish(source.len(), false);
+                stack.top.top.nodes.push(Node::Group(group));
+            }
+        }
+        if lines
+            && stack.top.top.nodes.is_empty()
+            && stack.pending.is_empty()
+            && rest.trim().is_empty()
+        {
+            let mut group = stack.top.finish(source.len(), false);
+            group.options.pop();
+            group
+        } else {
+            self.apply_weight(rest, &mut stack, source.len() - rest.len());
+            stack.top.finish(source.len(), false)
+        }
+    }
+
+    /// Load the group of options for the wildcard `name`, used at `index`, or report why it
+    /// can't be.
+    fn wildcard(&mut self, name: &str, index: usize) -> Option<Group> {
+        let span = index..index + name.len() + 4;
+        if let Some(group) = self.wildcards.get(name) {
+            return Some(group.clone());
+        }
+        if self.resolving.iter().any(|n| n == name) {
+            self.error(ParseErrorKind::RecursiveWildcard(name.to_string()), span);
+            return None;
+        }
+        let path = self
+            .options
+            .wildcard_dir
+            .as_ref()
+            .unwrap()
+            .join(format!("{name}.txt"));
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => {
+                let kind = ParseErrorKind::UnreadableWildcard(name.to_string(), Arc::new(err));
+                self.error(kind, span);
+                return None;
+            }
+        };
+        self.resolving.push(name.to_string());
+        let errors = self.errors.len();
+        let mut group = self.file(&path, text, true);
+        self.resolving.pop();
+        if group.options.is_empty() {
+            // Lines that only referred back to a wildcard being read were left out, and have
+            // already been reported as recursive
+            let recursive = self.errors[errors..]
+                .iter()
+                .any(|error| matches!(error.kind, ParseErrorKind::RecursiveWildcard(_)));
+            if !recursive {
+                self.error(ParseErrorKind::EmptyWildcard(name.to_string()), span);
+            }
+            return None;
+        }
+        group.span = span;
+        group.wildcard = Some(name.to_string());
+        self.wildcards.insert(name.to_string(), group.clone());
+        Some(group)
+    }
+
+    /// Parse `path`, with the contents `text`, as a new source.
+    fn file(&mut self, path: &Path, text: String, lines: bool) -> Group {
+        let text: Arc<str> = text.into();
+        self.sources.push(Source {
+            name: path.display().to_string(),
+            text: Arc::clone(&text),
+        });
+        let index = self.sources.len() - 1;
+        self.dirs
+            .push(path.parent().map(Path::to_path_buf).unwrap_or_default());
+        let outer = std::mem::replace(&mut self.source_index, index);
+        // Files almost always end in a line break, which shouldn't end up in the middle of
+        // whatever they're included into
+        let end = match text.strip_suffix('\n') {
+            Some(rest) if !lines => rest.strip_suffix('\r').unwrap_or(rest).len(),
+            _ => text.len(),
+        };
+        let mut group = self.group(&text[..end], 0, lines);
+        self.source_index = outer;
+        group.source = Some(index);
+        group
+    }
+
+    /// Parse the file at `path`, included with the directive at `span`, into a group, or
+    /// report why it can't be.
+    fn include(&mut self, path: &str, span: Span) -> Option<Group> {
+        let resolved = self.dirs[self.source_index].join(path);
+        let canonical = fs::canonicalize(&resolved).unwrap_or_else(|_| resolved.clone());
+        if self.including.contains(&canonical) {
+            self.error(ParseErrorKind::RecursiveInclude(path.to_string()), span);
+            return None;
+        }
+        let text = match fs::read_to_string(&resolved) {
+            Ok(text) => text,
+            Err(err) => {
+                let kind = ParseErrorKind::UnreadableInclude(path.to_string(), Arc::new(err));
+                self.error(kind, span);
+                return None;
+            }
+        };
+        self.including.push(canonical);
+        let mut group = self.file(&resolved, text, false);
+        self.including.pop();
+        group.span = span;
+        group.include = Some(path.to_string());
+        Some(group)
+    }
+}
+
+/// Parse `source` into a template, reporting every error found if it isn't valid.
+pub fn parse(source: &str, options: &ParseOptions) -> Result<Template, ParseErrors> {
+    parse_range(source, 0..source.len(), options)
+}
+
+/// Parse the part of `source` within `range` into a template. Spans, including those of any
+/// errors, still refer to the whole of `source`.
+pub fn parse_range(
+    source: &str,
+    range: Span,
+    options: &ParseOptions,
+) -> Result<Template, ParseErrors> {
+    parse_shared(source.into(), range, options)
+}
+
+/// Give `group` and every group nested within it an id, counting on from `next_id`. Groups
+/// read from a wildcard are copies of each other, so only get ids of their own here.
+fn number_groups(group: &mut Group, next_id: &mut usize) {
+    group.id = *next_id;
+    *next_id += 1;
+    for node in group
+        .options
+        .iter_mut()
+        .flat_map(|choice| &mut choice.nodes)
+    {
+        if let Node::Group(nested) = node {
+            number_groups(nested, next_id);
+        }
+    }
+}
+
+/// [`parse_range`], keeping a reference to `source` rather than a copy of it, so that many
+/// templates can be parsed from one file without copying it for each.
+pub(crate) fn parse_shared(
+    source: Arc<str>,
+    range: Span,
+    options: &ParseOptions,
+) -> Result<Template, ParseErrors> {
+    let mut parser = Parser {
+        options,
+        variables: Variables::default(),
+        wildcards: HashMap::new(),
+        resolving: Vec::new(),
+        sources: vec![Source {
+            name: options
+                .source_name
+                .clone()
+                .unwrap_or_else(|| "<template>".to_string()),
+            text: Arc::clone(&source),
+        }],
+        dirs: vec![options.include_dir.clone().unwrap_or_default()],
+        source_index: 0,
+        including: options
+            .source_path
+            .iter()
+            .map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+            .collect(),
+        errors: Vec::new(),
+    };
+    let source = &*source;
+    let (positive, negative) = match split_negative(source, range.clone()) {
+        Some((positive, negative)) => (positive, Some(negative)),
+        None => (range, None),
+    };
+    let mut root = parser.group(&source[..positive.end], positive.start, false);
+    let mut negative =
+        negative.map(|negative| parser.group(&source[..negative.end], negative.start, false));
+    let mut variables = parser.variables.finish(&mut parser.errors);
+    if parser.errors.is_empty() {
+        let mut next_id = 0;
+        let roots = std::iter::once(&mut root).chain(&mut negative);
+        for group in roots.chain(variables.iter_mut().map(|variable| &mut variable.group)) {
+            number_groups(group, &mut next_id);
+        }
+        Ok(Template {
+            root,
+            negative,
+            variables,
+            sources: parser.sources,
+        })
+    } else {
+        let mut errors = parser.errors;
+        errors.sort_by_key(|error| (error.source_index, error.span.start));
+        Err(ParseErrors {
+            errors,
+            sources: parser.sources,
+        })
+    }
+}
@@ -0,0 +1,112 @@
+//! Generated prompts and the record of how they were produced.
+
+use crate::ast::{Group, Span};
+use serde::Serialize;
+
+/// A generated prompt along with the choices that produced it.
+#[derive(Clone, Debug, Serialize)]
+pub struct Prompt {
+    pub text: String,
+    /// The negative prompt generated alongside, if the template has a negative section.
+    /// Variables expand to the same choice in both.
+    pub negative: Option<String>,
+    /// Seed that regenerates this prompt with [`Template::generate_seeded`], if it was
+    /// generated from one.
+    ///
+    /// [`Template::generate_seeded`]: crate::Template::generate_seeded
+    pub seed: Option<u64>,
+    /// Every choice made, in the order the groups were visited; nested groups come after the
+    /// group containing them. Groups with only one option are left out.
+    pub choices: Vec<Pick>,
+}
+
+impl Prompt {
+    /// The generated text, which determines whether two prompts are the same.
+    pub fn key(&self) -> (String, Option<String>) {
+        (self.text.clone(), self.negative.clone())
+    }
+}
+
+/// A single option chosen while generating a prompt.
+#[derive(Clone, Debug, Serialize)]
+pub struct Pick {
+    /// Span of the group the option was chosen from.
+    pub span: Span,
+    /// Wildcard or included file `span` refers to, or `None` for the template itself.
+    pub file: Option<String>,
+    /// Index of the chosen option within the group.
+    pub option: usize,
+    /// What the chosen option expanded to.
+    pub text: String,
+    pub weight: f64,
+    /// The option's share of the total weight of its group, once reshaped by any temperature.
+    pub probability: f64,
+}
+
+/// Collects the choices made while expanding a template.
+#[derive(Default)]
+pub(crate) struct Trace {
+    pub picks: Vec<Pick>,
+    file: Option<String>,
+}
+
+/// Position in a [`Trace`] to return to once an option has been expanded.
+pub(crate) struct Checkpoint {
+    start: usize,
+    pick: Option<usize>,
+    file: Option<String>,
+}
+
+impl Trace {
+    /// Record that option `index` of `group` is about to be expanded.
+    pub fn begin(&mut self, group: &Group, index: usize, temperature: f64) -> Checkpoint {
+        let file = match group.file() {
+            Some(name) => self.file.replace(name.clone()),
+            None => self.file.clone(),
+        };
+        let start = self.picks.len();
+        if group.options.len() == 1 {
+            return Checkpoint {
+                start,
+                pick: None,
+                file,
+            };
+        }
+        let choice = &group.options[index];
+        let weights = group.weights(temperature);
+        let weight_sum: f64 = weights.iter().sum();
+        self.picks.push(Pick {
+            span: group.span.clone(),
+            file: file.clone(),
+            option: index,
+            text: String::new(),
+            weight: choice.weight,
+            probability: if weight_sum > 0.0 {
+                weights[index] / weight_sum
+            } else {
+                1.0
+            },
+        });
+        Checkpoint {
+            start,
+            pick: Some(start),
+            file,
+        }
+    }
+
+    /// Finish recording an option, which expanded to `text`.
+    pub fn end(&mut self, checkpoint: Checkpoint, text: &str) {
+        if let Some(pick) = checkpoint.pick {
+            self.picks[pick].text = text.to_string();
+        }
+        self.file = checkpoint.file;
+    }
+
+    /// Finish recording an option like [`Trace::end`], but remove it and everything chosen
+    /// within it from the trace, returning them instead.
+    pub fn end_detached(&mut self, checkpoint: Checkpoint, text: &str) -> Vec<Pick> {
+        let start = checkpoint.start;
+        self.end(checkpoint, text);
+        self.picks.split_off(start)
+    }
+}
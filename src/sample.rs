@@ -0,0 +1,390 @@
+//! Random selection of options from a parsed template.
+
+use crate::ast::{Choice, Group, Node};
+use crate::normalize::Normalization;
+use crate::prompt::{Pick, Prompt, Trace};
+use crate::rng::{self, SamplingAlgorithm};
+use crate::split::distribute;
+use crate::Template;
+use clap::ValueEnum;
+use rand::RngCore;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Heuristic used to pick an option from each group in place of random selection.
+#[derive(ValueEnum, Clone, Debug, Serialize)]
+pub enum ChoiceGuidance {
+    /// Choose at random according to the weights, as without guidance; useful for exempting
+    /// groups in a template from guidance given for the rest
+    Random,
+    Shortest,
+    Longest,
+    LeastLikely,
+    MostLikely,
+    /// Take each group's options in order, one per visit, starting over after the last
+    Sequential,
+    /// Take each group's options once per round, in a new random order every round
+    RoundRobin,
+    /// Take each group's options in exact proportion to their weights over the batch, in
+    /// random order
+    Stratified,
+}
+
+impl ChoiceGuidance {
+    /// Whether this guidance spreads choices over a batch of prompts, rather than picking the
+    /// same way for every prompt. Prompts generated with it depend on those before them in the
+    /// batch, so can't be regenerated from their seed alone.
+    pub fn is_batched(&self) -> bool {
+        matches!(
+            self,
+            ChoiceGuidance::Sequential | ChoiceGuidance::RoundRobin | ChoiceGuidance::Stratified
+        )
+    }
+}
+
+/// State carried from one prompt to the next by guidance that spreads choices over a batch:
+/// the options each group has left to hand out before it starts another round. A prompt
+/// generated outside of a batch is treated as a batch of one.
+#[derive(Clone, Debug)]
+pub struct Batch {
+    size: usize,
+    /// Options left in the current round, keyed by [`Group::id`].
+    decks: HashMap<usize, Vec<usize>>,
+}
+
+impl Batch {
+    /// Prepare to generate `size` prompts.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size: size.max(1),
+            decks: HashMap::new(),
+        }
+    }
+
+    /// A full round of options from `group`. Sequential rounds are reversed, as options are
+    /// drawn from the end.
+    fn round(&self, group: &Group, guidance: &ChoiceGuidance, temperature: f64) -> Vec<usize> {
+        let mut possible: Vec<_> = group.possible_options().map(|(index, _)| index).collect();
+        match guidance {
+            ChoiceGuidance::Stratified => {
+                let all_weights = group.weights(temperature);
+                let weights: Vec<_> = possible.iter().map(|&index| all_weights[index]).collect();
+                possible
+                    .into_iter()
+                    .zip(distribute(self.size, &weights))
+                    .flat_map(|(index, count)| std::iter::repeat_n(index, count))
+                    .collect()
+            }
+            ChoiceGuidance::Sequential => {
+                possible.reverse();
+                possible
+            }
+            _ => possible,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GenerationOptions {
+    pub choice_guidance: Option<ChoiceGuidance>,
+    pub sampling_algorithm: SamplingAlgorithm,
+    /// Clean up applied to every generated prompt, if any.
+    pub normalization: Option<Normalization>,
+    /// Reshapes the weights of every group's options, as described for [`Group::weights`].
+    /// Defaults to 1, using the weights as written.
+    pub temperature: Option<f64>,
+}
+
+/// Walks a parsed template, choosing one option from every group it passes through.
+pub struct Sampler<'a, R: ?Sized> {
+    template: &'a Template,
+    rng: &'a mut R,
+    options: &'a GenerationOptions,
+    values: Vec<Option<String>>,
+    trace: Option<Trace>,
+    batch: Option<&'a mut Batch>,
+}
+
+/// An option expanded ahead of time, along with the choices made within it.
+struct Expansion {
+    text: String,
+    picks: Vec<Pick>,
+}
+
+impl<'a, R: RngCore + ?Sized> Sampler<'a, R> {
+    pub fn new(template: &'a Template, rng: &'a mut R, options: &'a GenerationOptions) -> Self {
+        Self {
+            template,
+            rng,
+            options,
+            values: vec![None; template.variables.len()],
+            trace: None,
+            batch: None,
+        }
+    }
+
+    /// Generate prompts as part of `batch`, for guidance that spreads choices over a batch.
+    pub fn with_batch(self, batch: &'a mut Batch) -> Self {
+        Self {
+            batch: Some(batch),
+            ..self
+        }
+    }
+
+    /// Generate a single prompt, along with its negative prompt if the template has one,
+    /// recording the choices made along the way.
+    pub fn generate_prompt(&mut self) -> Prompt {
+        self.trace = Some(Trace::default());
+        let text = self.generate();
+        // Sampled after the positive prompt, so that adding a negative section to a template
+        // doesn't change the positive prompts its seeds produce
+        let negative = self
+            .template
+            .negative
+            .as_ref()
+            .map(|negative| self.sample_root(negative));
+        let choices = self.trace.take().unwrap().picks;
+        Prompt {
+            text,
+            negative,
+            seed: None,
+            choices,
+        }
+    }
+
+    /// Generate a single prompt. Variables are resolved up front, in order of first appearance,
+    /// so that they are chosen from exactly once regardless of where they are used.
+    pub fn generate(&mut self) -> String {
+        self.values.fill(None);
+        for index in 0..self.values.len() {
+            self.variable(index);
+        }
+        self.sample_root(&self.template.root)
+    }
+
+    /// Sample a top level group with the variables already resolved.
+    fn sample_root(&mut self, root: &Group) -> String {
+        let mut out = String::new();
+        self.sample_group(root, &mut out);
+        match &self.options.normalization {
+            Some(normalization) => normalization.apply(&out),
+            None => out,
+        }
+    }
+
+    fn variable(&mut self, index: usize) -> &str {
+        if self.values[index].is_none() {
+            let mut value = String::new();
+            self.sample_group(&self.template.variables[index].group, &mut value);
+            self.values[index] = Some(value);
+        }
+        self.values[index].as_deref().unwrap()
+    }
+
+    /// The temperature to reshape the weights of `group` with.
+    fn temperature(&self, group: &Group) -> f64 {
+        group
+            .temperature
+            .or(self.options.temperature)
+            .unwrap_or(1.0)
+    }
+
+    /// Choose an option from `group`, or several for a multiple selection group, and append
+    /// the expansion to `out`.
+    fn sample_group(&mut self, group: &Group, out: &mut String) {
+        let count = match &group.selection {
+            None => 1,
+            Some(selection) => {
+                let (min, max) = selection.bounds(group.possible_options().count());
+                match self.options.sampling_algorithm {
+                    SamplingAlgorithm::V1 => min + rng::below(self.rng, max - min + 1),
+                }
+            }
+        };
+        let guidance = group.guidance.as_ref();
+        let mut picks = match guidance.or(self.options.choice_guidance.as_ref()) {
+            None | Some(ChoiceGuidance::Random) => self
+                .choose_weighted(group, count)
+                .into_iter()
+                .map(|index| (index, None))
+                .collect(),
+            Some(guidance) if guidance.is_batched() => self
+                .draw(group, guidance, count)
+                .into_iter()
+                .map(|index| (index, None))
+                .collect(),
+            Some(guidance) => {
+                let mut ranked = self.rank(group, guidance);
+                ranked.truncate(count);
+                ranked
+            }
+        };
+        picks.sort_by_key(|&(index, _)| index);
+        let temperature = self.temperature(group);
+        for (n, (index, expansion)) in picks.into_iter().enumerate() {
+            if let (Some(selection), true) = (&group.selection, n > 0) {
+                out.push_str(&selection.separator);
+            }
+            match expansion {
+                Some(expansion) => {
+                    out.push_str(&expansion.text);
+                    if let Some(trace) = &mut self.trace {
+                        trace.picks.extend(expansion.picks);
+                    }
+                }
+                None => {
+                    let start = out.len();
+                    let checkpoint = self
+                        .trace
+                        .as_mut()
+                        .map(|trace| trace.begin(group, index, temperature));
+                    self.sample_choice(&group.options[index], out);
+                    if let Some(checkpoint) = checkpoint {
+                        self.trace.as_mut().unwrap().end(checkpoint, &out[start..]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn sample_choice(&mut self, choice: &Choice, out: &mut String) {
+        for node in &choice.nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Group(group) => self.sample_group(group, out),
+                Node::Variable(index) => out.push_str(self.variable(*index)),
+            }
+        }
+    }
+
+    /// Randomly pick `count` distinct options from `group` according to their weights, one
+    /// after another, returning their indices.
+    fn choose_weighted(&mut self, group: &Group, count: usize) -> Vec<usize> {
+        let mut remaining: Vec<_> = group.possible_options().map(|(index, _)| index).collect();
+        let weights = group.weights(self.temperature(group));
+        let mut picks = Vec::with_capacity(count);
+        for _ in 0..count {
+            let weight_sum = remaining.iter().map(|&index| weights[index]).sum::<f64>();
+            let weighted_index = match self.options.sampling_algorithm {
+                SamplingAlgorithm::V1 => rng::unit(self.rng),
+            };
+            let mut weighted_index = weighted_index * weight_sum;
+            let mut pick = remaining.len() - 1;
+            for (position, &index) in remaining[..pick].iter().enumerate() {
+                let weight = weights[index];
+                if weighted_index <= weight {
+                    pick = position;
+                    break;
+                }
+                weighted_index -= weight;
+            }
+            picks.push(remaining.remove(pick));
+        }
+        picks
+    }
+
+    /// Take `count` distinct options from what's left of the current round for `group`,
+    /// starting new rounds as needed, returning their indices.
+    fn draw(&mut self, group: &Group, guidance: &ChoiceGuidance, count: usize) -> Vec<usize> {
+        let temperature = self.temperature(group);
+        let mut single = Batch::new(1);
+        let batch = self.batch.as_deref_mut().unwrap_or(&mut single);
+        let mut deck = batch.decks.remove(&group.id).unwrap_or_default();
+        let mut picks = Vec::with_capacity(count);
+        // Options already picked for this visit are held back for the next one
+        let mut held = Vec::new();
+        let mut rounds = 0;
+        while picks.len() < count {
+            if deck.is_empty() {
+                // A stratified round can leave out options with little weight entirely, so
+                // fall back to a round of every option if it doesn't have enough distinct ones
+                deck = match rounds {
+                    0 => batch.round(group, guidance, temperature),
+                    _ => batch.round(group, &ChoiceGuidance::RoundRobin, temperature),
+                };
+                rounds += 1;
+            }
+            let position = match guidance {
+                ChoiceGuidance::Sequential => deck.len() - 1,
+                _ => match self.options.sampling_algorithm {
+                    SamplingAlgorithm::V1 => rng::below(self.rng, deck.len()),
+                },
+            };
+            let index = deck.swap_remove(position);
+            if picks.contains(&index) {
+                held.push(index);
+            } else {
+                picks.push(index);
+            }
+        }
+        deck.extend(held.into_iter().rev());
+        batch.decks.insert(group.id, deck);
+        picks
+    }
+
+    /// Order the options of `group` from most to least preferred by `guidance`, along with
+    /// their expansions if they had to be generated to rank them.
+    fn rank(
+        &mut self,
+        group: &Group,
+        guidance: &ChoiceGuidance,
+    ) -> Vec<(usize, Option<Expansion>)> {
+        let temperature = self.temperature(group);
+        let mut ranked: Vec<_> = match guidance {
+            ChoiceGuidance::Longest | ChoiceGuidance::Shortest => {
+                let mut expansions: Vec<_> = group
+                    .options
+                    .iter()
+                    .enumerate()
+                    .filter(|&(index, _)| is_unlocked(group, index))
+                    .map(|(index, choice)| {
+                        let mut text = String::new();
+                        let checkpoint = self
+                            .trace
+                            .as_mut()
+                            .map(|trace| trace.begin(group, index, temperature));
+                        self.sample_choice(choice, &mut text);
+                        let picks = match checkpoint {
+                            Some(checkpoint) => {
+                                self.trace.as_mut().unwrap().end_detached(checkpoint, &text)
+                            }
+                            None => Vec::new(),
+                        };
+                        (index, Expansion { text, picks })
+                    })
+                    .collect();
+                expansions.sort_by_key(|(_, expansion)| expansion.text.len());
+                expansions
+                    .into_iter()
+                    .map(|(index, expansion)| (index, Some(expansion)))
+                    .collect()
+            }
+            ChoiceGuidance::MostLikely
+            | ChoiceGuidance::LeastLikely
+            | ChoiceGuidance::Random
+            | ChoiceGuidance::Sequential
+            | ChoiceGuidance::RoundRobin
+            | ChoiceGuidance::Stratified => {
+                let mut indices: Vec<_> = (0..group.options.len())
+                    .filter(|&index| is_unlocked(group, index))
+                    .collect();
+                indices.sort_by(|&a, &b| {
+                    group.options[a]
+                        .weight
+                        .partial_cmp(&group.options[b].weight)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                indices.into_iter().map(|index| (index, None)).collect()
+            }
+        };
+        if let ChoiceGuidance::Longest | ChoiceGuidance::MostLikely = guidance {
+            ranked.reverse();
+        }
+        ranked
+    }
+}
+
+/// Whether option `index` of `group` isn't ruled out by the group being locked to another.
+fn is_unlocked(group: &Group, index: usize) -> bool {
+    group.locked.is_none_or(|locked| locked == index)
+}
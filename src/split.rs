@@ -0,0 +1,109 @@
+//! Files holding several templates at once.
+
+use crate::ast::Span;
+use crate::diagnostic::ParseErrors;
+use crate::parse::{parse_shared, ParseOptions};
+use crate::Template;
+use clap::ValueEnum;
+use std::sync::Arc;
+
+/// How to divide a file into separate templates.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Split {
+    /// Every non-blank line is a template
+    Lines,
+    /// Every block of lines separated by blank lines is a template
+    Blocks,
+}
+
+/// Byte ranges of each of the templates in `source`, excluding line endings.
+pub fn ranges(source: &str, split: Split) -> Vec<Span> {
+    let mut ranges = Vec::new();
+    let mut block: Option<Span> = None;
+    let mut start = 0;
+    for line in source.split_inclusive('\n') {
+        let end = start + line.trim_end_matches(['\n', '\r']).len();
+        let blank = line.trim().is_empty();
+        match split {
+            Split::Lines if !blank => ranges.push(start..end),
+            Split::Lines => {}
+            Split::Blocks if blank => ranges.extend(block.take()),
+            Split::Blocks => block = Some(block.map_or(start, |block| block.start)..end),
+        }
+        start += line.len();
+    }
+    ranges.extend(block);
+    ranges
+}
+
+/// Parse every template in `source`, reporting the errors in all of them together.
+/// Templates that can only produce empty text, such as those made up of nothing but comments,
+/// are left out.
+pub fn parse_all(
+    source: &str,
+    split: Split,
+    options: &ParseOptions,
+) -> Result<Vec<Template>, ParseErrors> {
+    let mut templates = Vec::new();
+    let mut errors: Option<ParseErrors> = None;
+    let shared: Arc<str> = source.into();
+    for range in ranges(source, split) {
+        match (
+            parse_shared(Arc::clone(&shared), range, options),
+            &mut errors,
+        ) {
+            (Ok(template), _) => {
+                if template
+                    .root
+                    .options
+                    .iter()
+                    .any(|choice| !choice.nodes.is_empty())
+                {
+                    templates.push(template);
+                }
+            }
+            (Err(new), None) => errors = Some(new),
+            (Err(mut new), Some(errors)) => {
+                // Every template shares the file as source 0, followed by its own wildcards
+                let offset = errors.sources.len() - 1;
+                for error in &mut new.errors {
+                    if error.source_index > 0 {
+                        error.source_index += offset;
+                    }
+                }
+                errors.errors.extend(new.errors);
+                errors.sources.extend(new.sources.into_iter().skip(1));
+            }
+        }
+    }
+    errors.map_or(Ok(templates), Err)
+}
+
+/// Divide `total` prompts between templates in proportion to their `weights`, rounding so
+/// that the shares still add up to `total`. Templates are weighted equally if every weight
+/// is zero.
+pub fn distribute(total: usize, weights: &[f64]) -> Vec<usize> {
+    let sum: f64 = weights.iter().sum();
+    let shares: Vec<f64> = weights
+        .iter()
+        .map(|&weight| {
+            if sum > 0.0 {
+                total as f64 * weight / sum
+            } else {
+                total as f64 / weights.len() as f64
+            }
+        })
+        .collect();
+    let mut counts: Vec<usize> = shares.iter().map(|share| share.floor() as usize).collect();
+    // Hand out what's left to the templates that lost the most to rounding
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by(|&a, &b| {
+        let remainder = |index: usize| shares[index] - counts[index] as f64;
+        remainder(b).total_cmp(&remainder(a))
+    });
+    let assigned: usize = counts.iter().sum();
+    for &index in order.iter().take(total.saturating_sub(assigned)) {
+        counts[index] += 1;
+    }
+    counts
+}
@@ -0,0 +1,345 @@
+//! Statistics about the space of prompts a template can produce.
+
+use crate::ast::{Choice, Group, Node, Span};
+use crate::{GenerationOptions, Template};
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+
+/// Summary of the distribution of prompts a template produces under random selection.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// Number of distinct combinations of choices.
+    pub combinations: BigUint,
+    /// Shannon entropy of the distribution over combinations, in bits.
+    pub entropy: f64,
+    /// Probability of the single most likely combination.
+    pub max_probability: f64,
+    /// Probability of the single least likely combination.
+    pub min_probability: f64,
+    /// Every group in the template: variables first, then the rest in source order, each
+    /// followed by the groups nested within it.
+    pub groups: Vec<GroupStats>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GroupStats {
+    pub span: Span,
+    /// Wildcard or included file the span refers to, or `None` for the template itself.
+    pub file: Option<String>,
+    /// Number of groups this one is nested within.
+    pub depth: usize,
+    /// Name of the variable this group is bound to, if any.
+    pub variable: Option<String>,
+    /// Number of combinations this group can produce on its own.
+    pub combinations: BigUint,
+    /// Entropy of this group's choices, including those of any groups nested within it.
+    pub entropy: f64,
+    pub options: Vec<OptionStats>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OptionStats {
+    pub span: Span,
+    /// Short description of the option, with nested groups elided.
+    pub label: String,
+    pub weight: f64,
+    /// Probability of the option being chosen; for multiple selection groups, the probability
+    /// of it being among the options chosen.
+    pub probability: f64,
+}
+
+/// Largest number of options a multiple selection group can have for its distribution to be
+/// computed exactly. Beyond this, every subset of options is assumed to be equally likely.
+const MAX_EXACT_SELECTION: usize = 20;
+
+/// Every set of options that can be chosen from `group` together with its probability, as a
+/// list of option indices, with weights reshaped by `temperature`. Returns `None` if there are
+/// too many to list.
+fn subsets(group: &Group, temperature: f64) -> Option<Vec<(Vec<usize>, f64)>> {
+    let possible: Vec<_> = group.possible_options().map(|(index, _)| index).collect();
+    let all_weights = group.weights(temperature);
+    let weights: Vec<_> = possible.iter().map(|&index| all_weights[index]).collect();
+    let weight_sum: f64 = weights.iter().sum();
+    let Some(selection) = &group.selection else {
+        return Some(
+            possible
+                .iter()
+                .zip(&weights)
+                .map(|(&index, weight)| {
+                    let probability = if weight_sum > 0.0 {
+                        weight / weight_sum
+                    } else {
+                        1.0
+                    };
+                    (vec![index], probability)
+                })
+                // Options ruled out by the temperature are never chosen
+                .filter(|&(_, probability)| probability > 0.0)
+                .collect(),
+        );
+    };
+    if possible.len() > MAX_EXACT_SELECTION {
+        return None;
+    }
+    // Options are drawn one at a time without replacement, so the probability of reaching each
+    // set of options sums over the ways of drawing them in order; sets are visited before any
+    // set containing them.
+    let (min, max) = selection.bounds(possible.len());
+    let mut reach = vec![0.0; 1 << possible.len()];
+    reach[0] = 1.0;
+    let mut subsets = Vec::new();
+    for mask in 0..reach.len() {
+        let size = (mask as u32).count_ones() as usize;
+        if reach[mask] == 0.0 || size > max {
+            continue;
+        }
+        if size >= min {
+            let indices = (0..possible.len())
+                .filter(|bit| mask & (1 << bit) != 0)
+                .map(|bit| possible[bit])
+                .collect();
+            subsets.push((indices, reach[mask] / (max - min + 1) as f64));
+        }
+        let remaining: Vec<_> = (0..possible.len())
+            .filter(|bit| mask & (1 << bit) == 0)
+            .collect();
+        let remaining_weight: f64 = remaining.iter().map(|&bit| weights[bit]).sum();
+        for &bit in &remaining {
+            // With no weight left, as once the temperature has ruled out every other option,
+            // the sampler takes the first option remaining
+            let probability = if remaining_weight > 0.0 {
+                weights[bit] / remaining_weight
+            } else {
+                (bit == remaining[0]) as u8 as f64
+            };
+            reach[mask | (1 << bit)] += reach[mask] * probability;
+        }
+    }
+    Some(subsets)
+}
+
+fn binomial(n: usize, k: usize) -> BigUint {
+    (0..k).fold(BigUint::from(1u8), |product, i| product * (n - i) / (i + 1))
+}
+
+/// Count the combinations a group can produce.
+pub(crate) fn count_group(group: &Group) -> BigUint {
+    let counts = group
+        .possible_options()
+        .map(|(_, choice)| count_choice(choice));
+    let Some(selection) = &group.selection else {
+        return counts.sum();
+    };
+    // The combinations of k options are the sum over every set of k options of the product of
+    // their counts, built up one option at a time.
+    let (min, max) = selection.bounds(group.possible_options().count());
+    let mut sums = vec![BigUint::ZERO; max + 1];
+    sums[0] = BigUint::from(1u8);
+    for count in counts {
+        for k in (1..=max).rev() {
+            let term = &sums[k - 1] * &count;
+            sums[k] += term;
+        }
+    }
+    sums.drain(min..).sum()
+}
+
+fn count_choice(choice: &Choice) -> BigUint {
+    choice
+        .nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(_) | Node::Variable(_) => BigUint::from(1u8),
+            Node::Group(group) => count_group(group),
+        })
+        .product()
+}
+
+/// Count every distinct combination of choices `template` can produce. Each variable
+/// contributes its choices once, however many times it is used.
+pub fn count(template: &Template) -> BigUint {
+    template
+        .variables
+        .iter()
+        .map(|variable| count_group(&variable.group))
+        .chain(template.roots().map(count_group))
+        .product()
+}
+
+pub(crate) fn label(choice: &Choice, template: &Template) -> String {
+    choice
+        .nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(text) => text.clone(),
+            Node::Group(group) => match (&group.wildcard, &group.include) {
+                (Some(name), _) => format!("__{name}__"),
+                (_, Some(path)) => format!("{{@{path}}}"),
+                _ => "{...}".to_string(),
+            },
+            Node::Variable(index) => format!("${}", template.variables[*index].name),
+        })
+        .collect()
+}
+
+/// Accumulated entropy and extreme probabilities of some part of a template.
+#[derive(Clone, Copy)]
+struct Distribution {
+    entropy: f64,
+    max_probability: f64,
+    min_probability: f64,
+}
+
+impl Distribution {
+    const CERTAIN: Self = Self {
+        entropy: 0.0,
+        max_probability: 1.0,
+        min_probability: 1.0,
+    };
+
+    /// Combine two independent distributions.
+    fn and(self, other: Self) -> Self {
+        Self {
+            entropy: self.entropy + other.entropy,
+            max_probability: self.max_probability * other.max_probability,
+            min_probability: self.min_probability * other.min_probability,
+        }
+    }
+}
+
+struct Collector<'a> {
+    template: &'a Template,
+    options: &'a GenerationOptions,
+    groups: Vec<GroupStats>,
+}
+
+impl Collector<'_> {
+    fn group(
+        &mut self,
+        group: &Group,
+        file: &Option<String>,
+        depth: usize,
+        variable: Option<String>,
+    ) -> Distribution {
+        let index = self.groups.len();
+        self.groups.push(GroupStats {
+            span: group.span.clone(),
+            file: file.clone(),
+            depth,
+            variable,
+            combinations: count_group(group),
+            entropy: 0.0,
+            options: Vec::new(),
+        });
+        let file = &group.file().cloned().or_else(|| file.clone());
+        let inner: Vec<_> = group
+            .options
+            .iter()
+            .map(|choice| self.choice(choice, file, depth + 1))
+            .collect();
+        let mut probabilities = vec![0.0; group.options.len()];
+        let mut distribution = Distribution {
+            entropy: 0.0,
+            max_probability: 0.0,
+            min_probability: 1.0,
+        };
+        let temperature = group
+            .temperature
+            .or(self.options.temperature)
+            .unwrap_or(1.0);
+        match subsets(group, temperature) {
+            Some(subsets) => {
+                for (indices, probability) in subsets {
+                    let mut subset = Distribution {
+                        entropy: -probability.log2(),
+                        max_probability: probability,
+                        min_probability: probability,
+                    };
+                    for &index in &indices {
+                        probabilities[index] += probability;
+                        subset = subset.and(inner[index]);
+                    }
+                    distribution.entropy += probability * subset.entropy;
+                    distribution.max_probability =
+                        distribution.max_probability.max(subset.max_probability);
+                    distribution.min_probability =
+                        distribution.min_probability.min(subset.min_probability);
+                }
+            }
+            None => {
+                let possible: Vec<_> = group.possible_options().map(|(index, _)| index).collect();
+                let (min, max) = group.selection.as_ref().unwrap().bounds(possible.len());
+                let sets: BigUint = (min..=max).map(|k| binomial(possible.len(), k)).sum();
+                let probability = 1.0 / sets.to_f64().unwrap_or(f64::INFINITY);
+                distribution.entropy = -probability.log2();
+                distribution.max_probability = probability;
+                distribution.min_probability = probability;
+                let mean = (min + max) as f64 / 2.0 / possible.len() as f64;
+                for index in possible {
+                    probabilities[index] = mean;
+                    distribution.entropy += mean * inner[index].entropy;
+                }
+            }
+        }
+        let options = group
+            .options
+            .iter()
+            .zip(probabilities)
+            .map(|(choice, probability)| OptionStats {
+                span: choice.span.clone(),
+                label: label(choice, self.template),
+                weight: choice.weight,
+                probability,
+            })
+            .collect();
+        let stats = &mut self.groups[index];
+        stats.entropy = distribution.entropy;
+        stats.options = options;
+        distribution
+    }
+
+    fn choice(&mut self, choice: &Choice, file: &Option<String>, depth: usize) -> Distribution {
+        choice
+            .nodes
+            .iter()
+            .map(|node| match node {
+                Node::Group(group) => self.group(group, file, depth, None),
+                _ => Distribution::CERTAIN,
+            })
+            .fold(Distribution::CERTAIN, Distribution::and)
+    }
+}
+
+/// Compute statistics for `template`, as generated with `options`. Only their temperature is
+/// taken into account; choice guidance is not.
+pub fn stats(template: &Template, options: &GenerationOptions) -> Stats {
+    let mut collector = Collector {
+        template,
+        options,
+        groups: Vec::new(),
+    };
+    let mut distribution = Distribution::CERTAIN;
+    for variable in &template.variables {
+        let name = Some(variable.name.clone());
+        // Variables can be defined in an included or wildcard file, which their spans refer to
+        let file = (variable.source_index != 0)
+            .then(|| template.sources[variable.source_index].name.clone());
+        distribution = distribution.and(collector.group(&variable.group, &file, 0, name));
+    }
+    // A template without top level pipes is a single option; leave it out of the breakdown
+    // rather than listing the whole template as a group.
+    for root in template.roots() {
+        distribution = distribution.and(match root.options.as_slice() {
+            [choice] => collector.choice(choice, &None, 0),
+            _ => collector.group(root, &None, 0, None),
+        });
+    }
+    let groups = collector.groups;
+    Stats {
+        combinations: count(template),
+        entropy: distribution.entropy,
+        max_probability: distribution.max_probability,
+        min_probability: distribution.min_probability,
+        groups,
+    }
+}
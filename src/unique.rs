@@ -0,0 +1,184 @@
+//! Generation of prompts without repeats.
+
+use crate::ast::Group;
+use crate::enumerate::TooManyCombinations;
+use crate::prompt::Prompt;
+use crate::rng::{self, prompt_seed};
+use crate::{Batch, ChoiceGuidance, GenerationOptions, Template};
+use num_bigint::BigUint;
+use std::collections::HashSet;
+use thiserror::Error;
+
+/// Number of duplicates in a row after which random sampling is abandoned in favour of
+/// enumerating every remaining prompt.
+const MAX_CONSECUTIVE_DUPLICATES: usize = 1000;
+
+#[derive(Clone, Debug, Error)]
+pub enum UniqueError {
+    #[error("Template can only produce {available} distinct prompts, fewer than the {requested} requested")]
+    NotEnoughPrompts {
+        available: BigUint,
+        requested: usize,
+    },
+    #[error("Could not find enough distinct prompts by random sampling: {0}")]
+    TooManyCombinations(#[from] TooManyCombinations),
+}
+
+/// Iterator over randomly generated prompts that never repeats itself.
+///
+/// Prompts are sampled as usual, each from its own seed, and duplicates discarded. Once
+/// duplicates become so frequent that the space of prompts is nearly exhausted, every
+/// remaining distinct prompt is enumerated instead and handed out in random order; these
+/// prompts have no seed. Enumeration isn't possible when choice guidance or temperature rule
+/// out some options, so then running short of distinct prompts is an error.
+pub struct Unique<'a> {
+    template: &'a Template,
+    batch_seed: u64,
+    attempts: u64,
+    options: &'a GenerationOptions,
+    enumeration_limit: Option<u64>,
+    requested: usize,
+    remaining: usize,
+    seen: HashSet<(String, Option<String>)>,
+    batch: Batch,
+    leftovers: Option<Vec<Prompt>>,
+}
+
+impl<'a> Unique<'a> {
+    /// Prepare to generate `num` distinct prompts. Fails immediately if the template does not
+    /// have that many combinations; if sampling later has to fall back to enumeration, it
+    /// fails then when there are more than `enumeration_limit` combinations.
+    pub fn new(
+        template: &'a Template,
+        batch_seed: u64,
+        options: &'a GenerationOptions,
+        num: usize,
+        enumeration_limit: Option<u64>,
+    ) -> Result<Self, UniqueError> {
+        let available = template.count();
+        if available < BigUint::from(num) {
+            return Err(UniqueError::NotEnoughPrompts {
+                available,
+                requested: num,
+            });
+        }
+        Ok(Self {
+            template,
+            batch_seed,
+            attempts: 0,
+            options,
+            enumeration_limit,
+            requested: num,
+            remaining: num,
+            seen: HashSet::new(),
+            batch: Batch::new(num),
+            leftovers: None,
+        })
+    }
+
+    /// Number attempts, and so their seeds, from `first_index` rather than 0, for a batch that
+    /// follows on from others generated from the same batch seed.
+    pub fn starting_at(self, first_index: u64) -> Self {
+        Self {
+            attempts: first_index,
+            ..self
+        }
+    }
+
+    /// Index the seed of the next attempt would be derived from, which is where a batch
+    /// following on from this one should start.
+    pub fn next_index(&self) -> u64 {
+        self.attempts
+    }
+
+    /// Whether guidance or temperature keeps some group from ever choosing some of its
+    /// options, so that enumerating every combination would turn up prompts the sampler could
+    /// never have produced.
+    fn limits_choices(&self) -> bool {
+        self.template
+            .roots()
+            .chain(
+                self.template
+                    .variables
+                    .iter()
+                    .map(|variable| &variable.group),
+            )
+            .flat_map(Group::nested)
+            .filter(|group| group.possible_options().count() > 1)
+            .any(|group| {
+                let guidance = group.guidance.as_ref();
+                let guided = match guidance.or(self.options.choice_guidance.as_ref()) {
+                    None | Some(ChoiceGuidance::Random) => false,
+                    Some(guidance) => !guidance.is_batched(),
+                };
+                let temperature = group.temperature.or(self.options.temperature);
+                let weights = group.weights(temperature.unwrap_or(1.0));
+                guided
+                    || group
+                        .possible_options()
+                        .any(|(index, _)| weights[index] == 0.0)
+            })
+    }
+
+    /// Enumerate every prompt that hasn't been produced yet, in random order. Fails if some of
+    /// those couldn't have been sampled, as then they can't stand in for sampled prompts.
+    fn leftovers(&mut self) -> Result<Vec<Prompt>, UniqueError> {
+        if self.limits_choices() {
+            return Err(UniqueError::NotEnoughPrompts {
+                available: BigUint::from(self.seen.len()),
+                requested: self.requested,
+            });
+        }
+        let mut leftovers: Vec<_> = self
+            .template
+            .combinations(self.enumeration_limit)?
+            .map(|mut prompt| {
+                if let Some(normalization) = &self.options.normalization {
+                    normalization.apply_to(&mut prompt);
+                }
+                prompt
+            })
+            .filter(|prompt| self.seen.insert(prompt.key()))
+            .collect();
+        if leftovers.len() < self.remaining {
+            return Err(UniqueError::NotEnoughPrompts {
+                available: BigUint::from(self.seen.len()),
+                requested: self.requested,
+            });
+        }
+        rng::shuffle(&mut leftovers, &mut rng::seeded_rng(self.batch_seed));
+        Ok(leftovers)
+    }
+}
+
+impl Iterator for Unique<'_> {
+    type Item = Result<Prompt, UniqueError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        if self.leftovers.is_none() {
+            for _ in 0..MAX_CONSECUTIVE_DUPLICATES {
+                let seed = prompt_seed(self.batch_seed, self.attempts);
+                self.attempts += 1;
+                let prompt = self
+                    .template
+                    .generate_in_batch(seed, self.options, &mut self.batch);
+                if self.seen.insert(prompt.key()) {
+                    self.remaining -= 1;
+                    return Some(Ok(prompt));
+                }
+            }
+            match self.leftovers() {
+                Ok(leftovers) => self.leftovers = Some(leftovers),
+                Err(err) => {
+                    self.remaining = 0;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.remaining -= 1;
+        self.leftovers.as_mut().unwrap().pop().map(Ok)
+    }
+}
@@ -0,0 +1,12 @@
+//! Fixtures shared between the integration tests.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// An empty directory of its own for `test` to keep files in.
+pub fn directory(test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("promptifier-{}-{test}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    path
+}
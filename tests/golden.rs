@@ -0,0 +1,421 @@
+//! Locks the prompts produced from known seeds, so that a change to parsing, seeding or
+//! sampling which would stop archived seeds from reproducing their prompts fails loudly.
+//!
+//! These values must never be updated for an existing [`SamplingAlgorithm`]; changes in
+//! behaviour belong in a new version of it.
+
+use std::fs;
+
+mod common;
+
+use common::directory;
+use promptifier::{
+    prompt_seed, seeded_rng, Batch, ChoiceGuidance, GenerationOptions, Normalization, ParseOptions,
+    SamplingAlgorithm, Stats, Template, UniqueError,
+};
+use rand::RngCore;
+
+fn generate(source: &str, batch_seed: u64, num: u64, options: &GenerationOptions) -> Vec<String> {
+    let template = Template::parse(source, &ParseOptions::default()).unwrap();
+    (0..num)
+        .map(|index| {
+            template
+                .generate_seeded(prompt_seed(batch_seed, index), options)
+                .text
+        })
+        .collect()
+}
+
+/// Like [`generate`], but as a single batch, for guidance that spreads choices over one.
+fn generate_batch(
+    source: &str,
+    batch_seed: u64,
+    num: u64,
+    options: &GenerationOptions,
+) -> Vec<String> {
+    let template = Template::parse(source, &ParseOptions::default()).unwrap();
+    let mut batch = Batch::new(num as usize);
+    (0..num)
+        .map(|index| {
+            template
+                .generate_in_batch(prompt_seed(batch_seed, index), options, &mut batch)
+                .text
+        })
+        .collect()
+}
+
+fn v1() -> GenerationOptions {
+    GenerationOptions {
+        sampling_algorithm: SamplingAlgorithm::V1,
+        ..GenerationOptions::default()
+    }
+}
+
+#[test]
+fn prompt_seeds() {
+    // The first outputs of the reference SplitMix64 implementation seeded with 0.
+    let seeds: Vec<_> = (0..4).map(|index| prompt_seed(0, index)).collect();
+    assert_eq!(
+        seeds,
+        [
+            16294208416658607535,
+            7960286522194355700,
+            487617019471545679,
+            17909611376780542444
+        ]
+    );
+}
+
+#[test]
+fn seeded_rng_output() {
+    let mut rng = seeded_rng(42);
+    let values: Vec<_> = (0..4).map(|_| rng.next_u64()).collect();
+    assert_eq!(
+        values,
+        [
+            693385945204756564,
+            16436763086163553629,
+            3187728548114239752,
+            11482457584054113314
+        ]
+    );
+}
+
+#[test]
+fn weighted_nested_groups() {
+    assert_eq!(
+        generate(
+            "a {red|green:2|blue:0.5} {ball|{small|large:3} box}",
+            1,
+            8,
+            &v1()
+        ),
+        [
+            "a red large box",
+            "a green ball",
+            "a green ball",
+            "a green small box",
+            "a green ball",
+            "a green ball",
+            "a red large box",
+            "a green ball",
+        ]
+    );
+}
+
+#[test]
+fn variables() {
+    assert_eq!(
+        generate("{$animal=cat|dog|bird} and another $animal", 7, 6, &v1()),
+        [
+            "bird and another bird",
+            "bird and another bird",
+            "cat and another cat",
+            "dog and another dog",
+            "bird and another bird",
+            "bird and another bird",
+        ]
+    );
+}
+
+#[test]
+fn multiple_selection() {
+    assert_eq!(
+        generate("{1-3$$ and $$red|green:2|blue|yellow:0.5}", 3, 8, &v1()),
+        [
+            "red and green and blue",
+            "blue",
+            "red and green and blue",
+            "yellow",
+            "green",
+            "green",
+            "green",
+            "blue",
+        ]
+    );
+}
+
+#[test]
+fn guidance() {
+    let options = GenerationOptions {
+        choice_guidance: Some(ChoiceGuidance::Longest),
+        ..v1()
+    };
+    assert_eq!(
+        generate("{a|bbb|cc} {2$$x|yy|zzz}", 0, 2, &options),
+        ["bbb yy, zzz", "bbb yy, zzz"]
+    );
+}
+
+#[test]
+fn batched_guidance() {
+    let source = "{red|green|blue} {ball|{small|large:3} box}";
+    let batched = |guidance| GenerationOptions {
+        choice_guidance: Some(guidance),
+        ..v1()
+    };
+    assert_eq!(
+        generate_batch(source, 4, 6, &batched(ChoiceGuidance::Sequential)),
+        [
+            "red ball",
+            "green small box",
+            "blue ball",
+            "red large box",
+            "green ball",
+            "blue small box",
+        ]
+    );
+    assert_eq!(
+        generate_batch(source, 4, 6, &batched(ChoiceGuidance::RoundRobin)),
+        [
+            "red ball",
+            "blue small box",
+            "green large box",
+            "green ball",
+            "red large box",
+            "blue ball",
+        ]
+    );
+    assert_eq!(
+        generate_batch(source, 4, 6, &batched(ChoiceGuidance::Stratified)),
+        [
+            "red ball",
+            "green large box",
+            "blue large box",
+            "red small box",
+            "blue ball",
+            "green ball",
+        ]
+    );
+    assert_eq!(
+        generate_batch(
+            "{a:3|b|c:0.5} {x|y}",
+            2,
+            8,
+            &batched(ChoiceGuidance::Stratified)
+        ),
+        ["c x", "a x", "b y", "a y", "a y", "a x", "a y", "b x"]
+    );
+}
+
+#[test]
+fn temperature() {
+    let options = |temperature| GenerationOptions {
+        temperature: Some(temperature),
+        ..v1()
+    };
+    assert_eq!(
+        generate("{red|green:2|blue:0.5} {ball|box:3}", 1, 8, &options(0.5)),
+        [
+            "red box",
+            "green ball",
+            "green box",
+            "green box",
+            "green box",
+            "green box",
+            "red box",
+            "green box",
+        ]
+    );
+    // A group's own temperature takes the place of the one given for the whole template
+    assert_eq!(
+        generate(
+            "{red|green:2|blue:0.5} {~1: ball|box:3}",
+            1,
+            4,
+            &options(0.0)
+        ),
+        ["green box", "green ball", "green box", "green box"]
+    );
+    assert_eq!(
+        generate("{~3: red|green:2|blue:0.5}", 1, 8, &v1()),
+        ["red", "green", "green", "green", "red", "green", "red", "blue"]
+    );
+}
+
+#[test]
+fn unique() {
+    let template = Template::parse("{a|b}{c|d}", &ParseOptions::default()).unwrap();
+    let options = v1();
+    let prompts: Vec<_> = template
+        .generate_unique(5, &options, 4, None)
+        .unwrap()
+        .map(|prompt| prompt.unwrap().text)
+        .collect();
+    assert_eq!(prompts, ["bd", "ad", "bc", "ac"]);
+}
+
+#[test]
+fn unique_within_limited_choices() {
+    // Only prompts the options allow are produced, so these run out rather than enumerating
+    // the rest
+    for (source, expected, options) in [
+        (
+            "{a|b:2}",
+            "b",
+            GenerationOptions {
+                temperature: Some(0.0),
+                ..v1()
+            },
+        ),
+        (
+            "{a|bb|ccc}",
+            "ccc",
+            GenerationOptions {
+                choice_guidance: Some(ChoiceGuidance::Longest),
+                ..v1()
+            },
+        ),
+    ] {
+        let template = Template::parse(source, &ParseOptions::default()).unwrap();
+        let prompts: Vec<_> = template
+            .generate_unique(0, &options, 2, None)
+            .unwrap()
+            .collect();
+        assert_eq!(prompts[0].as_ref().unwrap().text, expected);
+        assert!(matches!(
+            prompts[1],
+            Err(UniqueError::NotEnoughPrompts { .. })
+        ));
+    }
+}
+
+#[test]
+fn unique_seeds_carry_on() {
+    // With seed 3 the second attempt repeats the first, so three attempts are used up
+    let (template, options) = (
+        Template::parse("{a|b}", &ParseOptions::default()).unwrap(),
+        v1(),
+    );
+    let mut unique = template.generate_unique(3, &options, 2, None).unwrap();
+    let seeds: Vec<_> = unique.by_ref().map(|prompt| prompt.unwrap().seed).collect();
+    let next = unique.next_index();
+    assert_eq!(next, 3);
+    let mut following = template
+        .generate_unique(3, &options, 1, None)
+        .unwrap()
+        .starting_at(next);
+    let seed = following.next().unwrap().unwrap().seed;
+    assert_eq!(seed, Some(prompt_seed(3, next)));
+    assert!(!seeds.contains(&seed));
+}
+
+/// Probability of each option of each group in `stats`, to three decimal places.
+fn odds(stats: &Stats) -> Vec<Vec<String>> {
+    stats
+        .groups
+        .iter()
+        .map(|group| {
+            group
+                .options
+                .iter()
+                .map(|option| format!("{:.3}", option.probability))
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn stats() {
+    let template = Template::parse("{a|b:3} {c|d} {2$$e:2|f|g}", &ParseOptions::default()).unwrap();
+    let stats = template.stats(&v1());
+    assert_eq!(stats.combinations.to_string(), "12");
+    assert_eq!(template.count().to_string(), "12");
+    assert_eq!(format!("{:.3}", stats.entropy), "3.295");
+    assert_eq!(format!("{:.5}", stats.max_probability), "0.15625");
+    assert_eq!(format!("{:.5}", stats.min_probability), "0.02083");
+    // A selected option's odds are those of it being among the options chosen
+    assert_eq!(
+        odds(&stats),
+        [
+            &["0.250", "0.750"][..],
+            &["0.500", "0.500"],
+            &["0.833", "0.583", "0.583"]
+        ]
+    );
+    let entropies: Vec<_> = stats
+        .groups
+        .iter()
+        .map(|group| format!("{:.3}", group.entropy))
+        .collect();
+    assert_eq!(entropies, ["0.811", "1.000", "1.483"]);
+}
+
+#[test]
+fn stats_of_included_variables() {
+    let dir = directory("stats");
+    fs::write(dir.join("animal.txt"), "{$animal=cat|dog}").unwrap();
+    let options = ParseOptions {
+        include_dir: Some(dir),
+        ..ParseOptions::default()
+    };
+    let template = Template::parse("{@animal.txt} and $animal", &options).unwrap();
+    let stats = template.stats(&v1());
+    let variable = &stats.groups[0];
+    assert_eq!(variable.variable.as_deref(), Some("animal"));
+    assert!(variable.file.as_deref().unwrap().ends_with("animal.txt"));
+    assert_eq!(stats.combinations.to_string(), "2");
+}
+
+#[test]
+fn temperature_in_stats() {
+    let template = Template::parse("{~0: a|b:2} {x|y:3}", &ParseOptions::default()).unwrap();
+    let options = GenerationOptions {
+        temperature: Some(0.5),
+        ..v1()
+    };
+    let stats = template.stats(&options);
+    assert_eq!(odds(&stats), [["0.000", "1.000"], ["0.100", "0.900"]]);
+    assert_eq!(format!("{:.3}", stats.min_probability), "0.100");
+}
+
+/// Every prompt `source` can produce, in the order they are enumerated.
+fn enumerate(source: &str) -> Vec<String> {
+    let template = Template::parse(source, &ParseOptions::default()).unwrap();
+    template
+        .combinations(None)
+        .unwrap()
+        .map(|prompt| prompt.text)
+        .collect()
+}
+
+#[test]
+fn combinations() {
+    assert_eq!(
+        enumerate("{a|b} {c|{d|e}}"),
+        ["a c", "a d", "a e", "b c", "b d", "b e"]
+    );
+    // Variables are decided once, and options that can never be chosen are left out
+    assert_eq!(enumerate("{$x=a|b} $x {c|d:0}"), ["a a c", "b b c"]);
+    // Selections are ranked by size, then in lexicographic order
+    assert_eq!(
+        enumerate("{1-2$$a|b|c}"),
+        ["a", "b", "c", "a, b", "a, c", "b, c"]
+    );
+    let template = Template::parse("{a|b} {c|d|e}", &ParseOptions::default()).unwrap();
+    let err = template.combinations(Some(5)).err().unwrap();
+    assert_eq!((err.count.to_string(), err.limit), ("6".to_string(), 5));
+    assert_eq!(template.combinations(Some(6)).unwrap().count(), 6);
+}
+
+#[test]
+fn normalization() {
+    let normalization = Normalization::default();
+    assert_eq!(normalization.apply("a , ; cat,"), "a, cat");
+    assert_eq!(normalization.apply(", a,,  b ;c ;"), "a, b;c");
+    assert_eq!(
+        normalization.apply("\n  \n a \t big  ball \r\n, x\n\n"),
+        "a big ball\r\nx"
+    );
+    // Without whitespace normalization, only the whitespace before a separator goes
+    let separators = Normalization {
+        whitespace: false,
+        separators: vec!['|'],
+    };
+    assert_eq!(separators.apply(" a  | |b , c |"), " a|b , c ");
+    let whitespace = Normalization {
+        whitespace: true,
+        separators: Vec::new(),
+    };
+    assert_eq!(whitespace.apply(" a ,  , b "), "a , , b");
+}
@@ -0,0 +1,176 @@
+//! Checks which output files get created, so that a failed run never leaves a file behind
+//! for the next one to refuse to overwrite.
+
+use std::fs;
+use std::process::Command;
+
+mod common;
+
+use common::directory;
+use promptifier::{Columns, Existing, Format, OutputFiles, Record};
+
+fn record(index: usize, prompt: &str) -> Record<'_> {
+    Record {
+        index,
+        template: None,
+        seed: None,
+        prompt,
+        negative: None,
+        choices: &[],
+    }
+}
+
+#[test]
+fn nothing_is_created_until_written() {
+    let path = directory("lazy").join("prompts.txt");
+    let out = OutputFiles::new(
+        &path,
+        Format::Text,
+        Columns::default(),
+        Existing::Refuse,
+        None,
+        None,
+        1,
+    )
+    .unwrap();
+    drop(out);
+    assert!(!path.exists());
+
+    let mut out = OutputFiles::new(
+        &path,
+        Format::Text,
+        Columns::default(),
+        Existing::Refuse,
+        None,
+        None,
+        1,
+    )
+    .unwrap();
+    out.write(&record(0, "a")).unwrap();
+    out.finish().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "a\n");
+}
+
+#[test]
+fn finishing_creates_an_empty_file() {
+    let path = directory("empty").join("prompts.json");
+    let out = OutputFiles::new(
+        &path,
+        Format::Json,
+        Columns::default(),
+        Existing::Refuse,
+        None,
+        None,
+        1,
+    )
+    .unwrap();
+    out.finish().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap().trim(), "[]");
+}
+
+#[test]
+fn existing_files_are_refused_up_front() {
+    let path = directory("refuse").join("prompts.txt");
+    fs::write(&path, "kept\n").unwrap();
+    let err = OutputFiles::new(
+        &path,
+        Format::Text,
+        Columns::default(),
+        Existing::Refuse,
+        None,
+        None,
+        1,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "kept\n");
+
+    let path = path.with_file_name("prompts-part1.txt");
+    fs::write(&path, "kept\n").unwrap();
+    let parts = path.with_file_name("prompts.txt");
+    assert!(OutputFiles::new(
+        &parts,
+        Format::Text,
+        Columns::default(),
+        Existing::Refuse,
+        None,
+        Some(2),
+        4,
+    )
+    .is_err());
+}
+
+#[test]
+fn failed_runs_leave_no_file() {
+    let directory = directory("failed");
+    let path = directory.join("prompts.txt");
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_promptifier"))
+            .args(args)
+            .arg("-o")
+            .arg(&path)
+            .output()
+            .unwrap()
+    };
+    for args in [
+        &["-x", "--max-combinations", "1", "{a|b}"][..],
+        &["-u", "-n", "5", "{a|b}"],
+    ] {
+        assert!(!run(args).status.success(), "{args:?} should fail");
+        assert!(!path.exists(), "{args:?} left {} behind", path.display());
+    }
+    assert!(run(&["-n", "2", "{a|b}"]).status.success());
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn csv_columns_cover_every_template() {
+    let path = directory("csv").join("prompts.csv");
+    let columns = Columns {
+        templates: true,
+        negatives: true,
+    };
+    let mut out =
+        OutputFiles::new(&path, Format::Csv, columns, Existing::Refuse, None, None, 2).unwrap();
+    out.write(&Record {
+        template: Some(0),
+        ..record(0, "a")
+    })
+    .unwrap();
+    out.write(&Record {
+        template: Some(1),
+        negative: Some("blurry"),
+        ..record(1, "b")
+    })
+    .unwrap();
+    out.finish().unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "index,template,seed,prompt,negative,choices\n0,0,,a,,[]\n1,1,,b,blurry,[]\n"
+    );
+}
+
+#[test]
+fn every_part_is_checked_up_front() {
+    let directory = directory("parts");
+    let path = directory.join("p.txt");
+    fs::write(directory.join("p-part2.txt"), "kept\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_promptifier"))
+        .args(["-n", "4", "--per-file", "2", "{a|b}", "-o"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("p-part2.txt"), "{stderr}");
+    assert!(!stderr.contains("--append"), "{stderr}");
+    assert!(!directory.join("p-part1.txt").exists());
+    // Only as many parts as are needed are checked
+    let output = Command::new(env!("CARGO_BIN_EXE_promptifier"))
+        .args(["-n", "2", "--per-file", "2", "{a|b}", "-o"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+}
@@ -0,0 +1,363 @@
+//! Checks the structure parsed from templates, and the errors reported for broken ones.
+
+use std::fs;
+use std::path::Path;
+
+mod common;
+
+use common::directory;
+use promptifier::{
+    escape, parse_weight, unescape, ChoiceGuidance, Group, Node, ParseOptions,
+    ParseWeightErrorKind, Severity, Template,
+};
+
+fn parse(source: &str) -> Template {
+    Template::parse(source, &ParseOptions::default()).unwrap()
+}
+
+/// `group` written back out as a template, with every weight and no escapes, so that its
+/// structure can be compared at a glance.
+fn render(group: &Group) -> String {
+    let options: Vec<_> = group
+        .options
+        .iter()
+        .map(|choice| {
+            let nodes: String = choice
+                .nodes
+                .iter()
+                .map(|node| match node {
+                    Node::Text(text) => text.clone(),
+                    Node::Group(group) => render(group),
+                    Node::Variable(index) => format!("${index}"),
+                })
+                .collect();
+            format!("{nodes}:{}", choice.weight)
+        })
+        .collect();
+    format!("{{{}}}", options.join("|"))
+}
+
+#[test]
+fn nested_groups() {
+    let template = parse("a {red|green:2|blue:0.5} {ball|{small|large:3} box}");
+    assert_eq!(
+        render(template.root()),
+        "{a {red:1|green:2|blue:0.5} {ball:1|{small:1|large:3} box:1}:1}"
+    );
+    let Node::Group(colors) = &template.root().options[0].nodes[1] else {
+        panic!("expected a group");
+    };
+    assert_eq!(colors.span, 2..24);
+    let spans: Vec<_> = colors.options.iter().map(|choice| &choice.span).collect();
+    assert_eq!(spans, [&(3..6), &(7..14), &(15..23)]);
+}
+
+#[test]
+fn top_level_options() {
+    assert_eq!(render(parse("a|b:3|").root()), "{a:1|b:3|:1}");
+    assert_eq!(render(parse("").root()), "{:1}");
+}
+
+#[test]
+fn variables() {
+    let template = parse("{$animal=cat|dog} and $animal, not $other{$other=bird}");
+    assert_eq!(render(template.root()), "{$0 and $0, not $1$1:1}");
+    let variables: Vec<_> = template
+        .variables()
+        .iter()
+        .map(|variable| (variable.name.as_str(), render(&variable.group)))
+        .collect();
+    assert_eq!(
+        variables,
+        [
+            ("animal", "{cat:1|dog:1}".to_string()),
+            ("other", "{bird:1}".to_string())
+        ]
+    );
+}
+
+#[test]
+fn wildcard_lines() {
+    let dir = directory("lines");
+    fs::write(
+        dir.join("colors.txt"),
+        "# Warm colors first\r\n\
+         red:2 # the favourite\r\n\
+         \r\n\
+         orange /* rarely */ :0.5\n\
+         C# blue\n   \n\
+         # Cool colors\n\
+         {light|dark} green\n\
+         /* spanning\n\
+         lines */ teal",
+    )
+    .unwrap();
+    let options = ParseOptions {
+        wildcard_dir: Some(dir),
+        ..ParseOptions::default()
+    };
+    let template = Template::parse("a __colors__ ball", &options).unwrap();
+    let Node::Group(colors) = &template.root().options[0].nodes[1] else {
+        panic!("expected a group");
+    };
+    assert_eq!(
+        render(colors),
+        "{red:2|orange  :0.5|C# blue:1|{light:1|dark:1} green:1| teal:1}"
+    );
+}
+
+#[test]
+fn escapes() {
+    assert_eq!(
+        render(parse(r"\{a\|b\} \\ \# \$x \/* c\:\d").root()),
+        r"{{a|b} \ # $x /* c:\d:1}"
+    );
+    assert_eq!(
+        render(parse(r"{ratio 16\:9|a\:b:2|c\\:3}").root()),
+        r"{{ratio 16:9:1|a:b:2|c\:3}:1}"
+    );
+}
+
+#[test]
+fn weights() {
+    assert_eq!(parse_weight(r"a\:b:2", false).unwrap(), ("a:b".into(), 2.0));
+    assert_eq!(parse_weight(r"16\:9", false).unwrap(), ("16:9".into(), 1.0));
+    assert_eq!(parse_weight("a: 0.5 ", false).unwrap(), ("a".into(), 0.5));
+    assert_eq!(
+        parse_weight("(a:1.2)", true).unwrap(),
+        ("(a:1.2)".into(), 1.0)
+    );
+    let err = parse_weight("a:b", false).unwrap_err();
+    assert_eq!((err.specifier.as_str(), err.index), ("b", 2));
+    // Negative weights are an error even when invalid weights are ignored
+    let err = parse_weight("a:-1", true).unwrap_err();
+    assert!(matches!(
+        err.parse_error,
+        ParseWeightErrorKind::NegativeWeight
+    ));
+}
+
+#[test]
+fn escape_round_trip() {
+    for text in [r"{a|b}", r"ratio 16:9 \ $x __y__ #z /*w*/"] {
+        assert_eq!(unescape(&escape(text)), text);
+        assert_eq!(render(parse(&escape(text)).root()), format!("{{{text}:1}}"));
+    }
+}
+
+/// Code, line and column of every error reported for `source`.
+fn errors(source: &str) -> Vec<(&'static str, usize, usize, usize, usize)> {
+    let errors = Template::parse(source, &ParseOptions::default())
+        .err()
+        .unwrap();
+    errors
+        .diagnostics()
+        .iter()
+        .map(|d| (d.code, d.line, d.column, d.end_line, d.end_column))
+        .collect()
+}
+
+#[test]
+fn recovers_from_brace_errors() {
+    assert_eq!(
+        errors("{a|b}} c}\n{d|{e|f:x}\ng {h"),
+        [
+            ("unexpected_closing_brace", 1, 6, 1, 7),
+            ("unexpected_closing_brace", 1, 9, 1, 10),
+            ("unclosed_brace", 2, 1, 2, 2),
+            ("invalid_weight_specifier", 2, 9, 2, 10),
+            ("unclosed_brace", 3, 3, 3, 4),
+        ]
+    );
+}
+
+#[test]
+fn columns_count_characters() {
+    assert_eq!(
+        errors("ünïcödé\n日本 {a|b:ß} }\n😀/*"),
+        [
+            ("invalid_weight_specifier", 2, 9, 2, 10),
+            ("unexpected_closing_brace", 2, 12, 2, 13),
+            ("unclosed_comment", 3, 2, 3, 4),
+        ]
+    );
+}
+
+#[test]
+fn weight_spans_skip_comments() {
+    assert_eq!(
+        errors("a:x/*é*/abc"),
+        [("invalid_weight_specifier", 1, 3, 1, 12)]
+    );
+    assert_eq!(
+        // The line break after the comment is kept, so is part of the specifier
+        errors("{a /* é */:ü # c\n|b}"),
+        [("invalid_weight_specifier", 1, 12, 2, 1)]
+    );
+}
+
+#[test]
+fn selection_errors() {
+    assert_eq!(
+        errors("{3-1$$a|b} {1-2-3$$c}"),
+        [
+            ("invalid_selection", 1, 2, 1, 5),
+            ("invalid_selection", 1, 13, 1, 18),
+        ]
+    );
+}
+
+#[test]
+fn temperature_errors() {
+    assert_eq!(
+        errors("{~abc: a|b} {~-1:c}"),
+        [
+            ("invalid_temperature", 1, 3, 1, 6),
+            ("invalid_temperature", 1, 15, 1, 17),
+        ]
+    );
+    let template = parse("{~0.5: a|b}");
+    let Node::Group(group) = &template.root().options[0].nodes[0] else {
+        panic!("expected a group");
+    };
+    assert_eq!(group.temperature, Some(0.5));
+}
+
+/// Code, line and column of every lint reported for `source`.
+fn lints(source: &str, options: &ParseOptions) -> Vec<(&'static str, usize, usize, usize, usize)> {
+    Template::parse(source, options)
+        .unwrap()
+        .lint()
+        .diagnostics()
+        .iter()
+        .map(|d| (d.code, d.line, d.column, d.end_line, d.end_column))
+        .collect()
+}
+
+#[test]
+fn every_lint() {
+    assert_eq!(
+        lints(
+            "{a||b} {c:0|d} {e}\n{f|f} {g:0|h:0}",
+            &ParseOptions::default()
+        ),
+        [
+            ("empty_option", 1, 4, 1, 4),
+            ("zero_weight", 1, 9, 1, 12),
+            ("single_option", 1, 16, 1, 19),
+            ("duplicate_option", 2, 4, 2, 5),
+            ("all_zero_weights", 2, 7, 2, 16),
+        ]
+    );
+    let ignoring = ParseOptions {
+        ignore_invalid_weight_literals: true,
+        ..ParseOptions::default()
+    };
+    assert_eq!(
+        lints("a (b:1.2) c", &ignoring),
+        [("emphasis_weight", 1, 3, 1, 10)]
+    );
+    assert!(lints("{a|b} {c|{d|e}}", &ParseOptions::default()).is_empty());
+}
+
+#[test]
+fn emphasis_weight_errors() {
+    let errors = Template::parse("a {b|c (d:1.2)}", &ParseOptions::default())
+        .err()
+        .unwrap();
+    let diagnostics = promptifier::error_diagnostics(&errors);
+    let found: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.severity, d.code, d.column, d.end_column))
+        .collect();
+    assert_eq!(found, [(Severity::Error, "emphasis_weight", 8, 15)]);
+    assert!(diagnostics[0]
+        .message
+        .starts_with("Invalid weight specifier"));
+}
+
+#[test]
+fn includes() {
+    let dir = directory("includes");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/colors.txt"), "{red|{@shade.txt}}").unwrap();
+    fs::write(dir.join("sub/shade.txt"), "light|dark").unwrap();
+    let options = ParseOptions {
+        include_dir: Some(dir.clone()),
+        ..ParseOptions::default()
+    };
+    // Paths in included files are resolved against their own directory
+    let template = Template::parse("a {@sub/colors.txt} ball", &options).unwrap();
+    let Node::Group(colors) = &template.root().options[0].nodes[1] else {
+        panic!("expected a group");
+    };
+    assert_eq!(colors.include.as_deref(), Some("sub/colors.txt"));
+    assert_eq!(render(colors), "{{red:1|{light:1|dark:1}:1}:1}");
+
+    let errors = Template::parse("{@missing.txt}", &options).err().unwrap();
+    assert_eq!(errors.diagnostics()[0].code, "unreadable_include");
+}
+
+#[test]
+fn include_cycles() {
+    let dir = directory("include-cycles");
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("a.txt"), "x {@sub/b.txt}").unwrap();
+    fs::write(dir.join("sub/b.txt"), "y {@../a.txt} {@c.txt}").unwrap();
+    fs::write(dir.join("sub/c.txt"), "z {@c.txt}").unwrap();
+    let options = ParseOptions {
+        include_dir: Some(dir.clone()),
+        source_name: Some("a.txt".to_string()),
+        source_path: Some(dir.join("a.txt")),
+        ..ParseOptions::default()
+    };
+    let errors = Template::parse("x {@sub/b.txt}", &options).err().unwrap();
+    // Each cycle is reported once, in the file that closes it
+    let found: Vec<_> = errors
+        .diagnostics()
+        .into_iter()
+        .map(|d| {
+            (
+                d.code,
+                Path::new(&d.file).strip_prefix(&dir).unwrap().to_owned(),
+                d.column,
+            )
+        })
+        .collect();
+    assert_eq!(
+        found,
+        [
+            ("recursive_include", Path::new("sub").join("b.txt"), 3),
+            ("recursive_include", Path::new("sub").join("c.txt"), 3),
+        ]
+    );
+}
+
+#[test]
+fn guidance_overrides() {
+    let template = parse("{$color=!round-robin:2$$red|green|blue} $color {!random: a|b} {!x}");
+    let color = &template.variables()[0].group;
+    assert!(matches!(color.guidance, Some(ChoiceGuidance::RoundRobin)));
+    assert_eq!(
+        color.selection.as_ref().map(|s| (s.min, s.max)),
+        Some((2, 2))
+    );
+    assert_eq!(render(color), "{red:1|green:1|blue:1}");
+    let Node::Group(random) = &template.root().options[0].nodes[4] else {
+        panic!("expected a group");
+    };
+    assert!(matches!(random.guidance, Some(ChoiceGuidance::Random)));
+    assert_eq!(render(random), "{a:1|b:1}");
+    // Without a colon there's no guidance, just text
+    let Node::Group(text) = &template.root().options[0].nodes[6] else {
+        panic!("expected a group");
+    };
+    assert!(text.guidance.is_none());
+    assert_eq!(render(text), "{!x:1}");
+
+    // Names are matched ignoring case
+    assert_eq!(
+        errors("{!bogus: a|b} {!Sequential:c}"),
+        [("unknown_guidance", 1, 3, 1, 8)]
+    );
+}